@@ -1,36 +1,126 @@
 use anyhow::*;
+use itertools::Itertools;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use crate::{
+    history::HistoryList,
     keyboard::output_char::OutputChar,
-    keyboard::{chord::Chord, key_code::KeyCode, Backend, KeyEvent},
-    mappings::Mappings,
+    keyboard::{chord::Chord, key_code::KeyCode, Backend, KeyEvent, TimedKeyEvent},
+    mappings::{Mapping, Mappings, Space},
 };
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppConfig {
+    /// maximum time between the first and the last key of a chord going down
+    pub press_window: Duration,
+    /// minimum time all keys of a chord need to be held down together
+    pub min_hold: Duration,
+    /// keep a separate chord state for every device, such that keys of different devices never form a chord together
+    pub per_device: bool,
+    /// print every chord that is typed
+    pub verbose: bool,
+    /// only print what would be typed, instead of writing it
+    pub dry_run: bool,
+    /// keys that suspend chord detection while they are held, in addition to Ctrl, Alt and Meta
+    pub suspend_keys: Vec<KeyCode>,
+    /// chord that undoes the last chord, typing its keys instead of its output
+    pub undo: Option<Chord>,
+    /// chord that replaces the output of the last chord with its next candidate output, like repeating the chord does
+    pub next_candidate: Option<Chord>,
+    /// capitalize the output of chords that start a sentence
+    pub sentence_case: bool,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            press_window: Duration::from_millis(50),
+            min_hold: Duration::from_millis(20),
+            per_device: false,
+            verbose: false,
+            dry_run: false,
+            suspend_keys: Vec::new(),
+            undo: None,
+            next_candidate: None,
+            sentence_case: true,
+        }
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct KeyPressState<K: Eq> {
     pressed_keys: Vec<(K, bool)>,
+    first_press: Option<Duration>,
+    last_press: Option<Duration>,
+    first_release: Option<Duration>,
+    /// shift keys that were held while any of the keys was down
+    shifts: Vec<K>,
 }
 
 impl<K: Eq> Default for KeyPressState<K> {
     fn default() -> Self {
         KeyPressState {
             pressed_keys: Vec::new(),
+            first_press: None,
+            last_press: None,
+            first_release: None,
+            shifts: Vec::new(),
         }
     }
 }
 
 impl<K: Eq> KeyPressState<K> {
-    pub fn press(&mut self, k: K) {
+    pub fn press(&mut self, k: K, time: Duration) {
+        self.first_press.get_or_insert(time);
+        self.last_press = Some(time);
         self.pressed_keys.push((k, true));
     }
-    pub fn release(&mut self, k: &K) {
-        if let Some((_, ref mut is_pressed)) =
-            self.pressed_keys.iter_mut().find(|(key, _)| key == k)
+    /// release the first press of the key that is still held, as the same key might be held on several devices.
+    pub fn release(&mut self, k: &K, time: Duration) {
+        if let Some((_, ref mut is_pressed)) = self
+            .pressed_keys
+            .iter_mut()
+            .find(|(key, pressed)| key == k && *pressed)
         {
             *is_pressed = false;
+            self.first_release.get_or_insert(time);
         }
     }
+    /// time since the first key of the current state went down.
+    pub fn time_since_first_press(&self, now: Duration) -> Option<Duration> {
+        self.first_press.map(|first| now.saturating_sub(first))
+    }
+    /// time during which all keys of the current state were held down together.
+    pub fn held_together(&self) -> Option<Duration> {
+        Some(self.first_release?.saturating_sub(self.last_press?))
+    }
+    /// record a shift key being held while keys of the current state are down.
+    pub fn shift(&mut self, k: K) {
+        if !self.shifts.contains(&k) {
+            self.shifts.push(k);
+        }
+    }
+    /// the distinct shift keys held while keys of the current state were down.
+    pub fn shifts(&self) -> &[K] {
+        &self.shifts
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pressed_keys.is_empty()
+    }
+    /// whether the key is part of the current state, regardless of it being released already.
+    pub fn contains(&self, k: &K) -> bool {
+        self.pressed_keys.iter().any(|(key, _)| key == k)
+    }
     pub fn clear(&mut self) -> Vec<K> {
+        self.first_press = None;
+        self.last_press = None;
+        self.first_release = None;
+        self.shifts.clear();
         self.pressed_keys.drain(..).map(|(k, _)| k).collect()
     }
     pub fn none_released(&self) -> Option<bool> {
@@ -52,81 +142,1346 @@ impl<K: Eq> KeyPressState<K> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::keyboard::{
+        layout::{test_layout, Layout},
+        output_char::output_chars_from_string,
+    };
+    use crate::mappings::{parse_entries, Format};
     use pretty_assertions::assert_eq;
+    use std::cell::RefCell;
 
     #[test]
     fn test_stuff() {
         let mut state = KeyPressState::default();
+        let t = Duration::from_millis;
 
         assert_eq!(None, state.all_released());
         assert_eq!(None, state.none_released());
-        state.press('a');
+        state.press('a', t(0));
         assert_eq!(Some(true), state.none_released());
         assert_eq!(Some(false), state.all_released());
-        state.press('b');
+        state.press('b', t(10));
         assert_eq!(Some(true), state.none_released());
         assert_eq!(Some(false), state.all_released());
-        state.release(&'b');
+        state.release(&'b', t(40));
         assert_eq!(Some(false), state.none_released());
         assert_eq!(Some(false), state.all_released());
-        state.release(&'a');
+        state.release(&'a', t(50));
         assert_eq!(Some(false), state.none_released());
         assert_eq!(Some(true), state.all_released());
+        assert_eq!(Some(t(30)), state.held_together());
+        assert_eq!(Some(t(60)), state.time_since_first_press(t(60)));
+    }
+
+    #[test]
+    fn test_same_key_pressed_twice() {
+        let mut state = KeyPressState::default();
+        let t = Duration::from_millis;
+
+        state.press('a', t(0));
+        state.press('a', t(10));
+        state.release(&'a', t(40));
+        assert_eq!(Some(false), state.all_released());
+        state.release(&'a', t(50));
+        assert_eq!(Some(true), state.all_released());
+    }
+
+    /// Backend that replays a fixed list of events and records everything that gets sent.
+    struct TestBackend {
+        events: Vec<TimedKeyEvent>,
+        sent: RefCell<Vec<KeyEvent>>,
+        exclusive: bool,
+    }
+
+    impl Backend for TestBackend {
+        fn handle_events<F: FnMut(TimedKeyEvent)>(&self, f: F) -> Result<()> {
+            self.events.iter().copied().for_each(f);
+            Ok(())
+        }
+        fn is_exclusive(&self) -> bool {
+            self.exclusive
+        }
+        fn send_key_event(&self, event: KeyEvent) -> Result<()> {
+            self.sent.borrow_mut().push(event);
+            Ok(())
+        }
+    }
+
+    /// Run the app on the given events, given as tuples of time in milliseconds, device and event,
+    /// and return the events it sent.
+    fn run_app_with(config: AppConfig, events: &[(u64, usize, KeyEvent)]) -> Vec<KeyEvent> {
+        run_backend_with(config, events, true)
+    }
+
+    fn run_backend_with(
+        config: AppConfig,
+        events: &[(u64, usize, KeyEvent)],
+        exclusive: bool,
+    ) -> Vec<KeyEvent> {
+        let mappings = Mappings::from_reader(r#"{"ab": "c"}"#.as_bytes(), None, None).unwrap();
+        let backend = TestBackend {
+            events: events
+                .iter()
+                .map(|&(millis, device, event)| TimedKeyEvent {
+                    event,
+                    time: Duration::from_millis(millis),
+                    device,
+                })
+                .collect(),
+            sent: RefCell::new(Vec::new()),
+            exclusive,
+        };
+        let mut app = App::new(backend, mappings, config).unwrap();
+        app.run().unwrap();
+        app.backend.sent.into_inner()
+    }
+
+    /// Run the app on events of a single device, given as pairs of time in milliseconds and event.
+    fn run_app(events: &[(u64, KeyEvent)]) -> Vec<KeyEvent> {
+        let events: Vec<_> = events
+            .iter()
+            .map(|&(millis, event)| (millis, 0, event))
+            .collect();
+        run_app_with(AppConfig::default(), &events)
+    }
+
+    /// assert that the given events get forwarded unchanged.
+    fn assert_forwarded(events: &[(u64, KeyEvent)]) {
+        let expected: Vec<_> = events.iter().map(|(_, event)| *event).collect();
+        assert_eq!(expected, run_app(events));
+    }
+
+    #[test]
+    fn test_chord_is_not_forwarded() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        let sent = run_app(&[
+            (0, KeyDown(KEY_A)),
+            (10, KeyDown(KEY_B)),
+            (100, KeyUp(KEY_B)),
+            (110, KeyUp(KEY_A)),
+        ]);
+        assert_eq!(vec![KeyDown(KEY_C), KeyUp(KEY_C)], sent);
+    }
+
+    #[test]
+    fn test_normal_typing_is_forwarded() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        assert_forwarded(&[
+            (0, KeyDown(KEY_A)),
+            (50, KeyUp(KEY_A)),
+            (100, KeyDown(KEY_X)),
+            (110, KeyDown(KEY_B)),
+            (150, KeyUp(KEY_X)),
+            (160, KeyDown(KEY_A)),
+            (200, KeyUp(KEY_B)),
+            (210, KeyUp(KEY_A)),
+        ]);
+    }
+
+    #[test]
+    fn test_rolled_keys_are_not_a_chord() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        // second key pressed too late
+        assert_forwarded(&[
+            (0, KeyDown(KEY_A)),
+            (80, KeyDown(KEY_B)),
+            (100, KeyUp(KEY_A)),
+            (120, KeyUp(KEY_B)),
+        ]);
+        // keys not held down together for long enough
+        assert_forwarded(&[
+            (0, KeyDown(KEY_A)),
+            (20, KeyDown(KEY_B)),
+            (25, KeyUp(KEY_A)),
+            (60, KeyUp(KEY_B)),
+        ]);
+    }
+
+    #[test]
+    fn test_unknown_keys_end_chords() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        // the keypad 1 comes after the a that was pressed before it
+        assert_forwarded(&[
+            (0, KeyDown(KEY_A)),
+            (10, Opaque { code: 79, value: 1 }),
+            (30, Opaque { code: 79, value: 0 }),
+            (100, KeyUp(KEY_A)),
+        ]);
+    }
+
+    #[test]
+    fn test_per_device_state() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        let events = [
+            (0, 0, KeyDown(KEY_A)),
+            (10, 1, KeyDown(KEY_B)),
+            (100, 1, KeyUp(KEY_B)),
+            (110, 0, KeyUp(KEY_A)),
+        ];
+        assert_eq!(
+            vec![KeyDown(KEY_C), KeyUp(KEY_C)],
+            run_app_with(AppConfig::default(), &events)
+        );
+
+        let config = AppConfig {
+            per_device: true,
+            ..AppConfig::default()
+        };
+        assert_eq!(
+            vec![KeyDown(KEY_B), KeyUp(KEY_B), KeyDown(KEY_A), KeyUp(KEY_A)],
+            run_app_with(config, &events)
+        );
+    }
+
+    #[test]
+    fn test_shortcuts_are_forwarded() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        assert_forwarded(&[
+            (0, KeyDown(KEY_LEFTCTRL)),
+            (100, KeyDown(KEY_A)),
+            (110, KeyDown(KEY_B)),
+            (200, KeyUp(KEY_B)),
+            (210, KeyUp(KEY_A)),
+            (300, KeyUp(KEY_LEFTCTRL)),
+        ]);
+
+        // modifier pressed while the chord keys are already down
+        assert_forwarded(&[
+            (0, KeyDown(KEY_A)),
+            (10, KeyDown(KEY_B)),
+            (20, KeyDown(KEY_LEFTALT)),
+            (200, KeyUp(KEY_B)),
+            (210, KeyUp(KEY_A)),
+            (300, KeyUp(KEY_LEFTALT)),
+        ]);
+    }
+
+    #[test]
+    fn test_suspend_keys() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        let config = AppConfig {
+            suspend_keys: vec![KEY_CAPSLOCK],
+            ..AppConfig::default()
+        };
+        let events = [
+            (0, 0, KeyDown(KEY_CAPSLOCK)),
+            (100, 0, KeyDown(KEY_A)),
+            (110, 0, KeyDown(KEY_B)),
+            (200, 0, KeyUp(KEY_B)),
+            (210, 0, KeyUp(KEY_A)),
+            (300, 0, KeyUp(KEY_CAPSLOCK)),
+        ];
+        let expected: Vec<_> = events.iter().map(|(_, _, event)| *event).collect();
+        assert_eq!(expected, run_app_with(config, &events));
+    }
+
+    #[test]
+    fn test_repeat_is_not_part_of_chord() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        assert_forwarded(&[
+            (0, KeyDown(KEY_A)),
+            (10, KeyDown(KEY_B)),
+            (500, Repeat(KEY_A)),
+            (530, Repeat(KEY_A)),
+            (550, KeyUp(KEY_B)),
+            (560, KeyUp(KEY_A)),
+        ]);
+
+        // repeats of keys that aren't part of the chord don't affect it
+        let sent = run_app(&[
+            (0, KeyDown(KEY_X)),
+            (500, Repeat(KEY_X)),
+            (600, KeyDown(KEY_A)),
+            (610, KeyDown(KEY_B)),
+            (630, Repeat(KEY_X)),
+            (700, KeyUp(KEY_B)),
+            (710, KeyUp(KEY_A)),
+            (800, KeyUp(KEY_X)),
+        ]);
+        assert_eq!(
+            vec![
+                KeyDown(KEY_X),
+                Repeat(KEY_X),
+                Repeat(KEY_X),
+                KeyDown(KEY_C),
+                KeyUp(KEY_C),
+                KeyUp(KEY_X)
+            ],
+            sent
+        );
+    }
+
+    #[test]
+    fn test_undo() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        let config = AppConfig {
+            undo: Some(Chord::from_string("<backspace>u")),
+            ..AppConfig::default()
+        };
+        let chord = [
+            (0, 0, KeyDown(KEY_B)),
+            (10, 0, KeyDown(KEY_A)),
+            (100, 0, KeyUp(KEY_B)),
+            (110, 0, KeyUp(KEY_A)),
+        ];
+        let undo = [
+            (200, 0, KeyDown(KEY_U)),
+            (210, 0, KeyDown(KEY_BACKSPACE)),
+            (300, 0, KeyUp(KEY_U)),
+            (310, 0, KeyUp(KEY_BACKSPACE)),
+        ];
+        let events: Vec<_> = chord.iter().chain(&undo).copied().collect();
+        assert_eq!(
+            vec![
+                KeyDown(KEY_C),
+                KeyUp(KEY_C),
+                KeyDown(KEY_BACKSPACE),
+                KeyUp(KEY_BACKSPACE),
+                KeyDown(KEY_B),
+                KeyDown(KEY_A),
+                KeyUp(KEY_B),
+                KeyUp(KEY_A),
+            ],
+            run_app_with(config.clone(), &events)
+        );
+
+        // after typing by hand, the output of the chord isn't right before the cursor anymore
+        let typed = [(150, 0, KeyDown(KEY_X)), (160, 0, KeyUp(KEY_X))];
+        let events: Vec<_> = chord.iter().chain(&typed).chain(&undo).copied().collect();
+        assert_eq!(
+            vec![
+                KeyDown(KEY_C),
+                KeyUp(KEY_C),
+                KeyDown(KEY_X),
+                KeyUp(KEY_X),
+                KeyDown(KEY_U),
+                KeyDown(KEY_BACKSPACE),
+                KeyUp(KEY_U),
+                KeyUp(KEY_BACKSPACE),
+            ],
+            run_app_with(config.clone(), &events)
+        );
+
+        // undo retypes the keys of the chord, so there's nothing left for a second undo
+        let second_chord = chord.map(|(time, device, event)| (time + 400, device, event));
+        let second_undo = undo.map(|(time, device, event)| (time + 400, device, event));
+        let events: Vec<_> = chord
+            .iter()
+            .chain(&second_chord)
+            .chain(&second_undo)
+            .chain(&undo.map(|(time, device, event)| (time + 800, device, event)))
+            .copied()
+            .collect();
+        assert_eq!(
+            vec![
+                KeyDown(KEY_C),
+                KeyUp(KEY_C),
+                KeyDown(KEY_C),
+                KeyUp(KEY_C),
+                KeyDown(KEY_BACKSPACE),
+                KeyUp(KEY_BACKSPACE),
+                KeyDown(KEY_B),
+                KeyDown(KEY_A),
+                KeyUp(KEY_B),
+                KeyUp(KEY_A),
+                KeyDown(KEY_U),
+                KeyDown(KEY_BACKSPACE),
+                KeyUp(KEY_U),
+                KeyUp(KEY_BACKSPACE),
+            ],
+            run_app_with(config, &events)
+        );
+    }
+
+    #[test]
+    fn test_erase_what_the_keys_typed() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        let config = AppConfig {
+            undo: Some(Chord::from_string("<backspace>u")),
+            ..AppConfig::default()
+        };
+        let events = [
+            (0, 0, KeyDown(KEY_A)),
+            (10, 0, KeyDown(KEY_B)),
+            (100, 0, KeyUp(KEY_B)),
+            (110, 0, KeyUp(KEY_A)),
+            // typed u and erased it again
+            (200, 0, KeyDown(KEY_U)),
+            (210, 0, KeyDown(KEY_BACKSPACE)),
+            (300, 0, KeyUp(KEY_U)),
+            (310, 0, KeyUp(KEY_BACKSPACE)),
+        ];
+        assert_eq!(
+            vec![
+                KeyDown(KEY_BACKSPACE),
+                KeyUp(KEY_BACKSPACE),
+                KeyDown(KEY_BACKSPACE),
+                KeyUp(KEY_BACKSPACE),
+                KeyDown(KEY_C),
+                KeyUp(KEY_C),
+                KeyDown(KEY_BACKSPACE),
+                KeyUp(KEY_BACKSPACE),
+                KeyDown(KEY_A),
+                KeyDown(KEY_B),
+                KeyUp(KEY_B),
+                KeyUp(KEY_A),
+            ],
+            run_backend_with(config, &events, false)
+        );
+    }
+
+    #[test]
+    fn test_nothing_is_forwarded_without_a_grab() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        let events = [
+            (0, 0, KeyDown(KEY_A)),
+            (10, 0, Opaque { code: 79, value: 1 }),
+            (30, 0, Opaque { code: 79, value: 0 }),
+            (100, 0, KeyUp(KEY_A)),
+        ];
+        assert_eq!(
+            Vec::<KeyEvent>::new(),
+            run_backend_with(AppConfig::default(), &events, false)
+        );
+    }
+
+    #[test]
+    fn test_typed_by() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        let typed = typed_by(&[
+            KeyDown(KEY_LEFTSHIFT),
+            KeyDown(KEY_A),
+            KeyUp(KEY_LEFTSHIFT),
+            KeyDown(KEY_B),
+            Repeat(KEY_B),
+            KeyDown(KEY_LEFTCTRL),
+            KeyDown(KEY_BACKSPACE),
+        ]);
+        assert_eq!("Abb<backspace>", typed.iter().join(""));
+        assert_eq!(Some((0, 2)), text_change(&typed));
+    }
+
+    /// The text typed by the given events, with backspaces applied.
+    fn typed_text(events: &[KeyEvent]) -> String {
+        let mut text = String::new();
+        for c in typed_by(events) {
+            match c {
+                OutputChar::Key {
+                    key: KeyCode::KEY_BACKSPACE,
+                    ..
+                } => {
+                    text.pop();
+                }
+                OutputChar::Key {
+                    key: KeyCode::KEY_ENTER,
+                    ..
+                } => text.push('\n'),
+                c => text.push_str(&c.to_string()),
+            }
+        }
+        text
+    }
+
+    /// An app that isn't fed any events, for calling `handle_keypress` directly.
+    fn app_with_mappings(mappings: &str, config: AppConfig) -> App<TestBackend> {
+        app_with_layout(mappings, config, None)
+    }
+
+    /// Like `app_with_mappings`, with outputs typed with the keys of the given layout.
+    fn app_with_layout(
+        mappings: &str,
+        config: AppConfig,
+        layout: Option<&Layout>,
+    ) -> App<TestBackend> {
+        let entries = parse_entries(mappings, Format::Json).unwrap();
+        let mappings = Mappings::from_entries(&entries, layout, None).unwrap();
+        let backend = TestBackend {
+            events: Vec::new(),
+            sent: RefCell::new(Vec::new()),
+            exclusive: true,
+        };
+        App::new(backend, mappings, config).unwrap()
+    }
+
+    /// Type the given chords, each with the given shift keys held, and return the text they typed.
+    fn type_chords(app: &App<TestBackend>, chords: &[&str], shifts: &[KeyCode]) -> String {
+        app.backend.sent.borrow_mut().clear();
+        for chord in chords {
+            assert!(app
+                .handle_keypress(Chord::from_string(chord), &[], shifts)
+                .unwrap());
+        }
+        typed_text(&app.backend.sent.borrow())
+    }
+
+    #[test]
+    fn test_spacing() {
+        let config = AppConfig {
+            undo: Some(Chord::from_string("uv")),
+            ..AppConfig::default()
+        };
+        let app = app_with_mappings(
+            r#"{
+                "version": 2,
+                "space": "auto",
+                "mappings": {
+                    "ab": "hello",
+                    "cd": "world",
+                    "ef": ".<cap-next>",
+                    "gh": "<attach>ing",
+                    "ij": "re<attach>",
+                    "kl": { "output": "the", "space": "after" }
+                }
+            }"#,
+            config,
+        );
+        let type_chords = |chords: &[&str]| type_chords(&app, chords, &[]);
+
+        assert_eq!(
+            "hello world. Helloing",
+            type_chords(&["ab", "cd", "ef", "ab", "gh"])
+        );
+        // typing by hand leaves nothing to space the next chord from
+        app.forward_events(Some(KeyEvent::KeyDown(KeyCode::KEY_X)))
+            .unwrap();
+        assert_eq!(
+            "the the. Reworld",
+            type_chords(&["kl", "kl", "ef", "ij", "cd"])
+        );
+
+        // undo types the space the punctuation took back again
+        app.forward_events(Some(KeyEvent::KeyDown(KeyCode::KEY_X)))
+            .unwrap();
+        assert_eq!("the ", type_chords(&["kl", "ef", "uv"]));
+    }
+
+    #[test]
+    fn test_candidates() {
+        use KeyCode::*;
+        let config = AppConfig {
+            next_candidate: Some(Chord::from_string("nc")),
+            ..AppConfig::default()
+        };
+        let app = app_with_mappings(
+            r#"{
+                "version": 2,
+                "space": "auto",
+                "mappings": {
+                    "ab": ["their", "there", "they're"],
+                    "cd": "is",
+                    "ef": ".<cap-next>"
+                }
+            }"#,
+            config,
+        );
+
+        assert_eq!("there", type_chords(&app, &["ab", "ab"], &[]));
+        // the next candidate chord cycles as well, and starts over after the last candidate
+        assert_eq!(
+            " is their",
+            type_chords(&app, &["cd", "ab", "nc", "nc", "nc"], &[])
+        );
+        assert_eq!(
+            ". They're",
+            type_chords(&app, &["ef", "ab", "ab", "ab"], &[])
+        );
+        // the replacement keeps the case of the output it replaces
+        app.forward_events(Some(KeyEvent::KeyDown(KEY_X))).unwrap();
+        assert_eq!(
+            "THEIR",
+            type_chords(&app, &["ab"], &[KEY_LEFTSHIFT, KEY_RIGHTSHIFT])
+        );
+        assert_eq!("THERE", type_chords(&app, &["ab"], &[]));
+        // without candidates, there's nothing for the next candidate chord to do
+        type_chords(&app, &["cd"], &[]);
+        assert!(!app
+            .handle_keypress(Chord::from_string("nc"), &[], &[])
+            .unwrap());
+    }
+
+    #[test]
+    fn test_layouts() {
+        let output_of = |app: &App<TestBackend>, chord: &str| {
+            assert!(app
+                .handle_keypress(Chord::from_string(chord), &[], &[])
+                .unwrap());
+            app.history
+                .borrow()
+                .newest()
+                .unwrap()
+                .output
+                .iter()
+                .join("")
+        };
+
+        // ü and ? are typed by keys that type punctuation on a US layout, and the other way around
+        let german = match test_layout("de", "") {
+            Some(layout) => layout,
+            None => return,
+        };
+        let app = app_with_layout(
+            r#"{
+                "version": 2,
+                "space": "auto",
+                "mappings": {
+                    "ab": { "output": "über", "space": "after" },
+                    "cd": "?",
+                    "ef": "_"
+                }
+            }"#,
+            AppConfig::default(),
+            Some(&german),
+        );
+        assert_eq!("über ", output_of(&app, "ab"));
+        assert_eq!("<backspace>?", output_of(&app, "cd"));
+        assert_eq!("Über ", output_of(&app, "ab"));
+        assert_eq!("_", output_of(&app, "ef"));
+        assert_eq!("über ", output_of(&app, "ab"));
+
+        let dvorak = test_layout("us", "dvorak").unwrap();
+        let app = app_with_layout(
+            r#"{ "version": 2, "space": "auto", "mappings": { "ab": "sun", "cd": "." } }"#,
+            AppConfig::default(),
+            Some(&dvorak),
+        );
+        assert_eq!("sun", output_of(&app, "ab"));
+        assert_eq!(".", output_of(&app, "cd"));
+        assert_eq!(" Sun", output_of(&app, "ab"));
+    }
+
+    #[test]
+    fn test_sentence_case() {
+        use KeyCode::*;
+        let mappings = r#"{
+            "version": 2,
+            "space": "auto",
+            "mappings": { "ab": "hello world", "cd": "!", "ef": "<enter>" }
+        }"#;
+        let app = app_with_mappings(mappings, AppConfig::default());
+        assert_eq!("hello world!", type_chords(&app, &["ab", "cd"], &[]));
+        assert_eq!(" Hello world", type_chords(&app, &["ab"], &[]));
+        // a new line doesn't start a sentence on its own
+        assert_eq!("\nhello world", type_chords(&app, &["ef", "ab"], &[]));
+        assert_eq!(" Hello world", type_chords(&app, &["ab"], &[KEY_LEFTSHIFT]));
+        assert_eq!(
+            " HELLO WORLD",
+            type_chords(&app, &["ab"], &[KEY_LEFTSHIFT, KEY_RIGHTSHIFT])
+        );
+
+        let config = AppConfig {
+            sentence_case: false,
+            ..AppConfig::default()
+        };
+        let app = app_with_mappings(mappings, config);
+        assert_eq!(
+            "hello world! hello world",
+            type_chords(&app, &["ab", "cd", "ab"], &[])
+        );
+    }
+
+    #[test]
+    fn test_shift_is_not_part_of_chord() {
+        use KeyCode::*;
+        use KeyEvent::*;
+        // held since before the chord, so it needs to be released for the output
+        let sent = run_app(&[
+            (0, KeyDown(KEY_LEFTSHIFT)),
+            (100, KeyDown(KEY_A)),
+            (110, KeyDown(KEY_B)),
+            (200, KeyUp(KEY_B)),
+            (210, KeyUp(KEY_A)),
+            (300, KeyUp(KEY_LEFTSHIFT)),
+        ]);
+        assert_eq!(
+            vec![
+                KeyDown(KEY_LEFTSHIFT),
+                KeyUp(KEY_LEFTSHIFT),
+                KeyDown(KEY_LEFTSHIFT),
+                KeyDown(KEY_C),
+                KeyUp(KEY_C),
+                KeyUp(KEY_LEFTSHIFT),
+                KeyDown(KEY_LEFTSHIFT),
+                KeyUp(KEY_LEFTSHIFT),
+            ],
+            sent
+        );
+
+        // typed by hand, shift stays in order with the keys it shifts
+        assert_forwarded(&[
+            (0, KeyDown(KEY_LEFTSHIFT)),
+            (100, KeyDown(KEY_A)),
+            (150, KeyUp(KEY_LEFTSHIFT)),
+            (200, KeyUp(KEY_A)),
+        ]);
+        assert_forwarded(&[
+            (0, KeyDown(KEY_A)),
+            (10, KeyDown(KEY_RIGHTSHIFT)),
+            (50, KeyUp(KEY_A)),
+            (100, KeyDown(KEY_B)),
+            (150, KeyUp(KEY_RIGHTSHIFT)),
+            (200, KeyUp(KEY_B)),
+        ]);
     }
+
+    #[test]
+    fn test_capitalize() {
+        let capitalize = |s: &str, all: bool| {
+            capitalize(&output_chars_from_string(s, None).unwrap(), all)
+                .iter()
+                .join("")
+        };
+        assert_eq!(" Hello world", capitalize(" hello world", false));
+        assert_eq!("Hello", capitalize("Hello", false));
+        assert_eq!("1 HELLO-WORLD", capitalize("1 hello-world", true));
+    }
+
+    #[test]
+    fn test_text_change() {
+        let change = |s| text_change(&output_chars_from_string(s, None).unwrap());
+        assert_eq!(Some((0, 5)), change("word "));
+        assert_eq!(Some((1, 4)), change("<backspace>ing "));
+        assert_eq!(Some((0, 2)), change("ab<backspace>c<cap-next>"));
+        assert_eq!(None, change("<ctrl-c>"));
+        assert_eq!(None, change("<left>"));
+    }
+
+    #[test]
+    fn test_capitalize_next() {
+        let backend = TestBackend {
+            events: Vec::new(),
+            sent: RefCell::new(Vec::new()),
+            exclusive: true,
+        };
+        let mappings = Mappings::from_reader("{}".as_bytes(), None, None).unwrap();
+        let app = App::new(backend, mappings, AppConfig::default()).unwrap();
+        let capitalize = |s: &str| {
+            app.apply_capitalization(&output_chars_from_string(s, None).unwrap())
+                .iter()
+                .join("")
+        };
+        assert_eq!("<cap-next>", capitalize("<cap-next>"));
+        assert_eq!(" Word", capitalize(" word"));
+        assert_eq!(" word", capitalize(" word"));
+        assert_eq!(". <cap-next>12 Ébc", capitalize(". <cap-next>12 ébc"));
+
+        capitalize("<cap-next>");
+        app.forward_events(Some(KeyEvent::KeyDown(KeyCode::KEY_X)))
+            .unwrap();
+        assert_eq!("word", capitalize("word"));
+    }
+}
+
+/// A chord that is currently being typed.
+#[derive(Debug, Default)]
+struct PendingChord {
+    keys: KeyPressState<KeyCode>,
+    /// events of the chord. These only get forwarded once it is clear that they don't form a chord.
+    buffered: Vec<KeyEvent>,
+    /// shift keys that are currently held down
+    held_shifts: Vec<KeyCode>,
+}
+
+/// A chord that was typed, and what it did to the text.
+#[derive(Debug)]
+pub struct HistoryEntry {
+    pub chord: Chord,
+    /// the output that was written for the chord
+    pub output: Vec<OutputChar>,
+    /// the key events of the chord, as they were typed
+    pub raw: Vec<KeyEvent>,
+    /// number of characters that were erased before the cursor, including the keys of the chord itself if they reached the applications
+    pub removed: usize,
+    /// number of characters the output added
+    pub typed: usize,
+    /// characters the output erased that are known, which undo types again
+    pub restore: Vec<OutputChar>,
+    /// the spacing of the mapping
+    pub space: Space,
+    /// which of the candidate outputs of the mapping was written
+    pub candidate: usize,
+    /// number of shift keys that were held during the chord
+    pub shifts: usize,
+    /// whether a `<cap-next>` of an earlier chord was still waiting for a letter to capitalize
+    pub capitalize_next: bool,
+}
+
+/// How typing the given output changes the text before the cursor: the number of characters it erases
+/// of the text that was there already, and the number of characters it adds.
+/// `None` if it does anything else, like moving the cursor.
+fn text_change(output: &[OutputChar]) -> Option<(usize, usize)> {
+    let (mut erased, mut added) = (0, 0);
+    for c in output {
+        match c {
+            OutputChar::Key {
+                key: KeyCode::KEY_BACKSPACE,
+                ..
+            } if added > 0 => added -= 1,
+            OutputChar::Key {
+                key: KeyCode::KEY_BACKSPACE,
+                ..
+            } => erased += 1,
+            OutputChar::Key {
+                key: KeyCode::KEY_ENTER | KeyCode::KEY_TAB,
+                ..
+            }
+            | OutputChar::Unicode(..) => added += 1,
+            _ if c.as_char().is_some() => added += 1,
+            OutputChar::CapitalizeNext | OutputChar::Attach => {}
+            _ => return None,
+        }
+    }
+    Some((erased, added))
+}
+
+/// The output typed by the given key events, if they reached the applications.
+/// Keys that don't type anything on their own, like Shift, are left out.
+fn typed_by(events: &[KeyEvent]) -> Vec<OutputChar> {
+    let mut shift = false;
+    let mut typed = Vec::new();
+    for event in events {
+        match *event {
+            KeyEvent::KeyDown(key) if key.is_shift() => shift = true,
+            KeyEvent::KeyUp(key) if key.is_shift() => shift = false,
+            KeyEvent::KeyDown(key) | KeyEvent::Repeat(key) => {
+                let c = OutputChar::Key {
+                    key,
+                    is_upper: shift,
+                    text: None,
+                };
+                if text_change(std::slice::from_ref(&c)).is_some() {
+                    typed.push(c);
+                }
+            }
+            KeyEvent::KeyUp(_) | KeyEvent::Opaque { .. } => {}
+        }
+    }
+    typed
+}
+
+/// Characters that are attached to whatever comes before them, without a space in between.
+const CLOSING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '}'];
+
+/// The last character of the output that types anything, or attaches to what comes next.
+fn last_char(output: &[OutputChar]) -> Option<&OutputChar> {
+    output
+        .iter()
+        .rev()
+        .find(|c| !matches!(c, OutputChar::CapitalizeNext))
+}
+
+/// Characters that end a sentence, so the output after them is capitalized.
+const SENTENCE_END: &[char] = &['.', '!', '?'];
+
+/// Whether the output types a single letter.
+fn is_letter(c: &OutputChar) -> bool {
+    c.as_char().is_some_and(char::is_alphabetic)
+}
+
+/// Capitalize the first letter of the output, or all of its letters.
+fn capitalize(output: &[OutputChar], all: bool) -> Vec<OutputChar> {
+    let mut capitalize = true;
+    output
+        .iter()
+        .map(|c| {
+            if !capitalize || !is_letter(c) {
+                return c.clone();
+            }
+            capitalize = all;
+            c.capitalized().unwrap_or_else(|| c.clone())
+        })
+        .collect()
+}
+
+/// State of a running `App`.
+#[derive(Debug, Default)]
+struct RunState {
+    /// chords in progress, by source device
+    pending: HashMap<usize, PendingChord>,
+    /// held keys that currently suspend chord detection
+    suspending: Vec<KeyCode>,
 }
 
 pub struct App<B: Backend> {
     backend: B,
-    mappings: Mappings,
-    //history: HistoryList<HistoryEntry>,
+    /// shared with whatever reloads the mappings while the app is running
+    mappings: Arc<RwLock<Mappings>>,
+    config: AppConfig,
+    /// whether the next letter typed by a chord should be capitalized, as requested by `<cap-next>`
+    capitalize_next: Cell<bool>,
+    /// chords typed since anything was typed by hand, the newest first
+    history: RefCell<HistoryList<HistoryEntry>>,
 }
 
 impl<B: Backend> App<B> {
-    pub fn new(backend: B, mappings: Mappings) -> Result<Self> {
+    pub fn new(backend: B, mappings: Mappings, config: AppConfig) -> Result<Self> {
         Ok(App {
-            mappings,
+            mappings: Arc::new(RwLock::new(mappings)),
             backend,
-            //history: HistoryList::new(50),
+            config,
+            capitalize_next: Cell::new(false),
+            history: RefCell::new(HistoryList::new(50)),
         })
     }
 
+    /// Handle to the mappings in use. Replacing them takes effect for the next chord.
+    pub fn mappings(&self) -> Arc<RwLock<Mappings>> {
+        self.mappings.clone()
+    }
+
     pub fn run(&mut self) -> Result<()> {
-        let mut state = KeyPressState::default();
+        let mut state = RunState::default();
+
+        self.backend.handle_events(|event| {
+            let result = self.handle_event(&mut state, event);
+            if let Err(err) = result {
+                eprintln!("Error handling key event: {:#?}", err);
+            }
+        })?;
+        Ok(())
+    }
+
+    fn handle_event(&self, state: &mut RunState, event: TimedKeyEvent) -> Result<()> {
+        match event.event {
+            KeyEvent::KeyDown(code) if self.suspends_chords(code) => {
+                if !state.suspending.contains(&code) {
+                    state.suspending.push(code);
+                }
+                // whatever was typed so far is part of a shortcut, not a chord
+                for pending in state.pending.values_mut() {
+                    pending.keys.clear();
+                    self.forward_events(pending.buffered.drain(..))?;
+                }
+                self.forward_events(Some(event.event))
+            }
+            KeyEvent::KeyUp(code) if state.suspending.contains(&code) => {
+                state.suspending.retain(|key| *key != code);
+                self.forward_events(Some(event.event))
+            }
+            _ if !state.suspending.is_empty() => self.forward_events(Some(event.event)),
+            _ => {
+                let source = if self.config.per_device {
+                    event.device
+                } else {
+                    0
+                };
+                self.handle_chord_event(state.pending.entry(source).or_default(), event)
+            }
+        }
+    }
 
-        self.backend.handle_events(|event| match event {
+    /// Whether holding the given key should keep any chords from being detected.
+    fn suspends_chords(&self, key: KeyCode) -> bool {
+        (key.is_modifier() && !key.is_shift()) || self.config.suspend_keys.contains(&key)
+    }
+
+    fn handle_chord_event(
+        &self,
+        pending: &mut PendingChord,
+        TimedKeyEvent { event, time, .. }: TimedKeyEvent,
+    ) -> Result<()> {
+        let PendingChord {
+            keys,
+            buffered,
+            held_shifts,
+        } = pending;
+        match event {
+            // it can't be part of a chord, so it ends the one in progress like any other key typed by hand
+            KeyEvent::Opaque { value, .. } => {
+                if value != 0 {
+                    keys.clear();
+                    self.forward_events(buffered.drain(..))?;
+                }
+                self.forward_events(Some(event))?;
+            }
+            // shift isn't part of the chord, but changes the case of its output
+            KeyEvent::KeyDown(code) | KeyEvent::KeyUp(code) | KeyEvent::Repeat(code)
+                if code.is_shift() =>
+            {
+                match event {
+                    KeyEvent::KeyDown(_) if !held_shifts.contains(&code) => held_shifts.push(code),
+                    KeyEvent::KeyUp(_) => held_shifts.retain(|held| *held != code),
+                    _ => {}
+                }
+                if keys.is_empty() {
+                    self.forward_events(Some(event))?;
+                } else {
+                    if event == KeyEvent::KeyDown(code) {
+                        keys.shift(code);
+                    }
+                    // keep the order, so keys typed by hand are still shifted as they were
+                    buffered.push(event);
+                }
+            }
             KeyEvent::KeyDown(code) => {
-                if state.none_released() != Some(false) {
-                    state.press(code);
+                let in_press_window = keys
+                    .time_since_first_press(time)
+                    .is_none_or(|elapsed| elapsed <= self.config.press_window);
+                if keys.none_released() != Some(false) && in_press_window {
+                    keys.press(code, time);
+                    for shift in held_shifts.iter() {
+                        keys.shift(*shift);
+                    }
+                    buffered.push(event);
                 } else {
-                    state.clear();
+                    keys.clear();
+                    self.forward_events(buffered.drain(..))?;
+                    self.forward_events(Some(event))?;
                 }
             }
+            // holding a key long enough for it to repeat means it's being typed, not chorded.
+            KeyEvent::Repeat(code) => {
+                if keys.contains(&code) {
+                    keys.clear();
+                    self.forward_events(buffered.drain(..))?;
+                }
+                self.forward_events(Some(event))?;
+            }
             KeyEvent::KeyUp(code) => {
-                state.release(&code);
-                if state.all_released() == Some(true) {
-                    let result = self.handle_keypress(Chord::from_key_codes(state.clear()));
-                    if let Err(err) = result {
-                        eprintln!("Error handling keypress: {:#?}", err);
+                if !keys.contains(&code) {
+                    return self.forward_events(Some(event));
+                }
+                keys.release(&code, time);
+                buffered.push(event);
+                if keys.all_released() == Some(true) {
+                    let is_chord = keys
+                        .held_together()
+                        .is_some_and(|held| held >= self.config.min_hold);
+                    let shifts = keys.shifts().to_vec();
+                    let keys = keys.clear();
+                    // shift keys held since before the chord are down for the applications as well,
+                    // which would turn all of the output into capitals
+                    let forwarded_shifts = held_shifts
+                        .iter()
+                        .filter(|shift| !buffered.contains(&KeyEvent::KeyDown(**shift)))
+                        .copied()
+                        .collect::<Vec<_>>();
+                    let handled = is_chord && {
+                        self.forward_events(forwarded_shifts.iter().map(|s| KeyEvent::KeyUp(*s)))?;
+                        let handled = self.handle_keypress(
+                            Chord::ordered_from_key_codes(keys),
+                            buffered,
+                            &shifts,
+                        )?;
+                        self.forward_events(
+                            forwarded_shifts.iter().map(|s| KeyEvent::KeyDown(*s)),
+                        )?;
+                        handled
+                    };
+                    if handled {
+                        buffered.clear();
+                    } else {
+                        self.forward_events(buffered.drain(..))?;
                     }
                 }
             }
-        })?;
+        }
         Ok(())
     }
 
-    pub fn handle_keypress(&self, chord: Chord) -> Result<()> {
-        println!("{:?}", chord);
+    /// Pass the given events on to the applications.
+    /// This only does anything if the backend is exclusive, as otherwise the events already reached them.
+    fn forward_events<I: IntoIterator<Item = KeyEvent>>(&self, events: I) -> Result<()> {
+        for event in events {
+            // anything typed by hand is what the capitalization was meant for,
+            // and leaves the output of earlier chords somewhere undo can't find it
+            let typed_by_hand = match event {
+                KeyEvent::KeyDown(key) => !key.is_modifier(),
+                KeyEvent::Opaque { value, .. } => value == 1,
+                _ => false,
+            };
+            if typed_by_hand {
+                self.capitalize_next.set(false);
+                self.history.borrow_mut().clear();
+            }
+            if self.backend.is_exclusive() {
+                self.backend.send_key_event(event)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the output mapped to the given chord, if there is any, or undo the last chord if it is the undo chord.
+    /// Repeating a chord with several candidate outputs, or the next candidate chord, replaces its output with the next one.
+    /// `raw` are the key events of the chord, which undo types instead of its output.
+    /// With one of the `shifts` held during the chord, its output is capitalized, and with both, all of it is.
+    /// Returns whether the chord was mapped to something.
+    pub fn handle_keypress(
+        &self,
+        chord: Chord,
+        raw: &[KeyEvent],
+        shifts: &[KeyCode],
+    ) -> Result<bool> {
+        if self.config.verbose {
+            println!("{:?}", chord);
+        }
+        // without an exclusive grab, the keys of the chord reached the applications as well
+        let (erased_by_keys, typed_by_keys) = if self.backend.is_exclusive() {
+            (0, 0)
+        } else {
+            text_change(&typed_by(raw)).unwrap_or_default()
+        };
+        if self.config.undo.as_ref() == Some(&chord.unordered()) {
+            return self.undo(&chord, erased_by_keys, typed_by_keys);
+        }
+
+        let mappings = self.mappings.read().unwrap();
+        let mapping = mappings.lookup_mapping(&chord);
+        if let Some((previous, mapping, candidate)) =
+            self.next_candidate(&chord, mapping, &mappings)
+        {
+            return self.replace_candidate(
+                previous,
+                mapping,
+                candidate,
+                erased_by_keys,
+                typed_by_keys,
+            );
+        }
+        let mapping = match mapping {
+            Some(mapping) => mapping,
+            None => return Ok(false),
+        };
+        if !self.config.dry_run {
+            self.erase(typed_by_keys)?;
+        }
+        self.type_output(
+            chord,
+            raw.to_vec(),
+            mapping,
+            0,
+            shifts.len(),
+            erased_by_keys + typed_by_keys,
+        )?;
+        Ok(true)
+    }
 
-        let result: Option<&Vec<OutputChar>> = self.mappings.lookup(&chord);
+    /// Write one of the candidate outputs of the mapping of a chord, and record it in the history.
+    /// `removed` is the number of characters that were erased before, such as the ones typed by the keys of the chord.
+    fn type_output(
+        &self,
+        chord: Chord,
+        raw: Vec<KeyEvent>,
+        mapping: &Mapping,
+        candidate: usize,
+        shifts: usize,
+        removed: usize,
+    ) -> Result<()> {
+        let capitalize_next = self.capitalize_next.get();
+        let (spaced, restore) = self.apply_spacing(&mapping.outputs[candidate], mapping.space);
+        let cased = match shifts {
+            0 if self.config.sentence_case && self.at_sentence_start() => {
+                capitalize(&spaced, false)
+            }
+            0 => spaced,
+            1 => capitalize(&spaced, false),
+            _ => capitalize(&spaced, true),
+        };
+        let result = self.apply_capitalization(&cased);
+        if self.config.dry_run {
+            println!("{:?} -> {}", chord, result.iter().join(""));
+        } else {
+            self.backend.write_chars(&result)?;
+        }
 
-        if let Some(result) = result {
-            for _ in 0..chord.len() {
+        let mut history = self.history.borrow_mut();
+        match text_change(&result) {
+            Some((erased, typed)) => {
+                history.push(HistoryEntry {
+                    chord,
+                    output: result,
+                    raw,
+                    removed: removed + erased,
+                    typed,
+                    restore,
+                    space: mapping.space,
+                    candidate,
+                    shifts,
+                    capitalize_next,
+                });
+            }
+            // the cursor might be anywhere now
+            None => history.clear(),
+        }
+        Ok(())
+    }
+
+    /// The last chord, its mapping and the candidate output to replace its output with, if the chord asks for that
+    /// by either repeating the last chord or being the next candidate chord. The last chord is taken off the history.
+    fn next_candidate<'m>(
+        &self,
+        chord: &Chord,
+        mapping: Option<&Mapping>,
+        mappings: &'m Mappings,
+    ) -> Option<(HistoryEntry, &'m Mapping, usize)> {
+        let mut history = self.history.borrow_mut();
+        let previous = history.newest()?;
+        let previous_mapping = mappings.lookup_mapping(&previous.chord)?;
+        let is_next = self.config.next_candidate.as_ref() == Some(&chord.unordered())
+            || mapping.is_some_and(|mapping| std::ptr::eq(mapping, previous_mapping));
+        if !is_next || previous_mapping.outputs.len() < 2 {
+            return None;
+        }
+        let candidate = (previous.candidate + 1) % previous_mapping.outputs.len();
+        Some((history.pop_newest()?, previous_mapping, candidate))
+    }
+
+    /// Replace the output of the last chord with another of its candidate outputs.
+    /// `erased_by_keys` and `typed_by_keys` are the characters the keys of the chord asking for it erased and typed themselves.
+    fn replace_candidate(
+        &self,
+        previous: HistoryEntry,
+        mapping: &Mapping,
+        candidate: usize,
+        erased_by_keys: usize,
+        typed_by_keys: usize,
+    ) -> Result<bool> {
+        if !self.config.dry_run {
+            self.erase(previous.typed.saturating_sub(erased_by_keys) + typed_by_keys)?;
+            self.backend.write_chars(&previous.restore)?;
+        }
+        // the replacement is typed as if it were the first output of the chord
+        self.capitalize_next.set(previous.capitalize_next);
+        let removed_by_output = text_change(&previous.output).map_or(0, |(erased, _)| erased);
+        self.type_output(
+            previous.chord,
+            previous.raw,
+            mapping,
+            candidate,
+            previous.shifts,
+            previous.removed - removed_by_output,
+        )?;
+        Ok(true)
+    }
+
+    /// Press backspace the given number of times.
+    fn erase(&self, count: usize) -> Result<()> {
+        if count > 0 {
+            for _ in 0..count {
                 self.backend.press_key(KeyCode::KEY_BACKSPACE)?;
             }
             std::thread::sleep(std::time::Duration::from_nanos(10));
-            self.backend.write_chars(result)?;
         }
-
         Ok(())
     }
+
+    /// Erase the output of the last chord and type its keys instead.
+    /// `erased_by_keys` and `typed_by_keys` are the characters the keys of the undo chord erased and typed themselves.
+    /// Characters the output of the last chord erased aren't restored, apart from the keys of the chord itself
+    /// and spaces it took back.
+    /// Returns whether there was a chord to undo.
+    fn undo(&self, chord: &Chord, erased_by_keys: usize, typed_by_keys: usize) -> Result<bool> {
+        let entry = match self.history.borrow_mut().pop_newest() {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        // the keys typed instead are typed by hand as far as the chords before are concerned,
+        // so their output isn't right before the cursor anymore
+        self.history.borrow_mut().clear();
+        self.capitalize_next.set(false);
+        if self.config.dry_run {
+            println!("{:?} -> undo {:?}", chord, entry.chord);
+            return Ok(true);
+        }
+        // like `<backspace>u`, the undo chord might have erased some of the output already
+        self.erase(entry.typed.saturating_sub(erased_by_keys) + typed_by_keys)?;
+        self.backend.write_chars(&entry.restore)?;
+        for event in entry.raw {
+            self.backend.send_key_event(event)?;
+        }
+        Ok(true)
+    }
+
+    /// Separate the output from the output of the previous chord, or attach it to it, as its spacing and `<attach>` ask for.
+    /// Returns the output, and the characters of the previous output that it erases.
+    fn apply_spacing(
+        &self,
+        output: &[OutputChar],
+        space: Space,
+    ) -> (Vec<OutputChar>, Vec<OutputChar>) {
+        let history = self.history.borrow();
+        // after typing by hand, there's no telling what comes before the output
+        let previous = match history.newest() {
+            Some(previous) => previous,
+            None => return (output.to_vec(), Vec::new()),
+        };
+        let space_key = OutputChar::from(KeyCode::KEY_SPACE);
+        let is_text = |c: &OutputChar| c.as_char().is_some_and(|c| !c.is_whitespace());
+        let previous_char = last_char(&previous.output);
+        let first_char = output
+            .iter()
+            .find(|c| !matches!(c, OutputChar::CapitalizeNext));
+        let attached = previous_char == Some(&OutputChar::Attach)
+            || first_char == Some(&OutputChar::Attach)
+            || first_char
+                .and_then(OutputChar::as_char)
+                .is_some_and(|c| CLOSING_PUNCTUATION.contains(&c));
+
+        let mut spaced = Vec::with_capacity(output.len() + 1);
+        let mut erased = Vec::new();
+        if attached {
+            // only take back a space the previous chord added by itself
+            if previous.space == Space::After
+                && previous_char.and_then(OutputChar::as_char) == Some(' ')
+            {
+                spaced.push(OutputChar::from(KeyCode::KEY_BACKSPACE));
+                erased.push(space_key);
+            }
+        } else if space == Space::Auto
+            && previous_char.is_some_and(is_text)
+            && first_char.is_some_and(is_text)
+        {
+            spaced.push(space_key);
+        }
+        spaced.extend_from_slice(output);
+        (spaced, erased)
+    }
+
+    /// Whether the output of the previous chord ended a sentence.
+    fn at_sentence_start(&self) -> bool {
+        let history = self.history.borrow();
+        let previous = match history.newest() {
+            Some(previous) => previous,
+            None => return false,
+        };
+        let last_text = previous
+            .output
+            .iter()
+            .rev()
+            .filter_map(OutputChar::as_char)
+            .find(|c| !c.is_whitespace());
+        last_text.is_some_and(|c| SENTENCE_END.contains(&c))
+    }
+
+    /// Capitalize the first letter after a `<cap-next>`, which might be in the output of an earlier chord.
+    fn apply_capitalization(&self, output: &[OutputChar]) -> Vec<OutputChar> {
+        let mut capitalize = self.capitalize_next.get();
+        let output = output
+            .iter()
+            .map(|c| match c {
+                OutputChar::CapitalizeNext => {
+                    capitalize = true;
+                    c.clone()
+                }
+                _ if capitalize && is_letter(c) => {
+                    capitalize = false;
+                    c.capitalized().unwrap_or_else(|| c.clone())
+                }
+                _ => c.clone(),
+            })
+            .collect();
+        self.capitalize_next.set(capitalize);
+        output
+    }
 }
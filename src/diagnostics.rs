@@ -0,0 +1,172 @@
+use anyhow::*;
+use itertools::Itertools;
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    keyboard::{
+        chord::{parse_keys, split_keys, Chord},
+        key_code::KeyCode,
+        layout::Layout,
+    },
+    mappings::{Location, MappingEntry},
+};
+
+/// Which finger presses each key, used to find chords that can't be pressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingers {
+    fingers: HashMap<KeyCode, String>,
+}
+
+impl Fingers {
+    /// Parse an object of finger names and the keys they press, written like chords,
+    /// such as `{"left index": "rtfgvb"}`.
+    pub fn from_reader<R: std::io::Read>(reader: R, layout: Option<&Layout>) -> Result<Self> {
+        let fingers: HashMap<String, String> =
+            serde_json::from_reader(reader).context("Failed to parse fingers")?;
+        let mut keys = HashMap::new();
+        for (finger, finger_keys) in fingers {
+            for key in parse_keys(&finger_keys, layout) {
+                if let Some(other) = keys.insert(key, finger.clone()) {
+                    bail!(
+                        "{} is assigned to both the {} and the {} finger",
+                        key.as_string(),
+                        other,
+                        finger
+                    );
+                }
+            }
+        }
+        Ok(Fingers { fingers: keys })
+    }
+
+    /// The finger that presses `key`, if it is assigned to one.
+    pub fn finger(&self, key: &KeyCode) -> Option<&str> {
+        self.fingers.get(key).map(String::as_str)
+    }
+}
+
+/// A problem with an entry of a mappings file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Find entries that won't do what they look like they do: chords that are the same as an earlier one in the same file,
+/// contain unknown keys or modifiers, or, if `fingers` is given, need one finger to press several keys.
+pub fn check_mappings(
+    entries: &[MappingEntry],
+    chord_layout: Option<&Layout>,
+    fingers: Option<&Fingers>,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut seen: HashMap<Chord, &MappingEntry> = HashMap::new();
+
+    for entry in entries {
+        let mut report = |message: String| {
+            diagnostics.push(Diagnostic {
+                location: entry.location.clone(),
+                message,
+            })
+        };
+
+        for part in split_keys(&entry.chord) {
+            if parse_keys(part, chord_layout) == [KeyCode::UNKNOWN] {
+                report(format!(
+                    "{:?} contains the unknown key {}",
+                    entry.chord, part
+                ));
+            }
+        }
+
+        let keys = parse_keys(&entry.chord, chord_layout);
+        for key in keys.iter().unique().filter(|key| key.is_modifier()) {
+            let consequence = if key.is_shift() {
+                "which is meant for typing capitals"
+            } else {
+                "which suspends chord detection, so it can never be typed"
+            };
+            report(format!(
+                "{:?} contains the modifier {}, {}",
+                entry.chord,
+                key.as_string(),
+                consequence
+            ));
+        }
+
+        if let Some(fingers) = fingers {
+            let by_finger = keys
+                .iter()
+                .unique()
+                .filter_map(|key| Some((fingers.finger(key)?, key)))
+                .into_group_map();
+            for (finger, finger_keys) in by_finger.into_iter().sorted() {
+                if finger_keys.len() > 1 {
+                    report(format!(
+                        "{:?} needs the {} finger to press {} at once",
+                        entry.chord,
+                        finger,
+                        finger_keys.iter().map(|key| key.as_string()).join(" and ")
+                    ));
+                }
+            }
+        }
+
+        // replacing a chord of an included file is what includes are for
+        let previous = seen.insert(entry.parse_chord(chord_layout), entry);
+        if let Some(previous) = previous.filter(|p| p.location.file == entry.location.file) {
+            report(format!(
+                "{:?} is the same chord as {:?} at {}, and replaces it",
+                entry.chord, previous.chord, previous.location
+            ));
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappings::{parse_entries, Format};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_check_mappings() {
+        let entries = parse_entries(
+            r#"{
+                "ab": "first",
+                "ba": "second",
+                "ba": { "output": "ordered", "ordered": true },
+                "a<nope>": "unknown",
+                "<leftctrl>c": "modifier",
+                "rf": "same finger",
+                "rj": "fine"
+            }"#,
+            Format::Json,
+        )
+        .unwrap();
+        let fingers = Fingers::from_reader(
+            r#"{ "left index": "rtfgvb", "right index": "yuhjnm" }"#.as_bytes(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "3:17: \"ba\" is the same chord as \"ab\" at 2:17, and replaces it",
+                "5:17: \"a<nope>\" contains the unknown key <nope>",
+                "6:17: \"<leftctrl>c\" contains the modifier <leftctrl>, which suspends chord detection, so it can never be typed",
+                "7:17: \"rf\" needs the left index finger to press r and f at once",
+            ],
+            check_mappings(&entries, None, Some(&fingers))
+                .iter()
+                .map(|diagnostic| diagnostic.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+}
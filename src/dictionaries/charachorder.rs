@@ -0,0 +1,253 @@
+use anyhow::*;
+use std::collections::HashMap;
+
+use super::{escape, Conversion, Skipped};
+use crate::{
+    keyboard::{
+        chord::{parse_keys, split_keys},
+        key_code::KeyCode,
+        layout::Layout,
+        output_char::OutputChar,
+    },
+    mappings::{Case, Location, MappingEntry, Space},
+};
+
+/// Names of the action keys that aren't written as the character they type.
+const ACTION_NAMES: &[(&str, KeyCode)] = &[
+    ("SPACE", KeyCode::KEY_SPACE),
+    ("BKSP", KeyCode::KEY_BACKSPACE),
+    ("DEL", KeyCode::KEY_DELETE),
+    ("ENTER", KeyCode::KEY_ENTER),
+    ("TAB", KeyCode::KEY_TAB),
+    ("ESC", KeyCode::KEY_ESC),
+];
+
+/// Parse the key of a chord input, which is either the character it types or the name of an action key.
+fn parse_action(action: &str) -> Result<KeyCode> {
+    if let Some((_, key)) = ACTION_NAMES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(action))
+    {
+        return Ok(*key);
+    }
+    let mut chars = action.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => match OutputChar::from_char(c)? {
+            OutputChar::Key {
+                key,
+                is_upper: false,
+                ..
+            } => Ok(key),
+            _ => bail!("{} can't be pressed as a single key", action),
+        },
+        _ => bail!("Unknown action key {}", action),
+    }
+}
+
+/// The way a key is written in a chord input.
+fn action_name(key: KeyCode) -> Option<String> {
+    if let Some((name, _)) = ACTION_NAMES.iter().find(|(_, k)| *k == key) {
+        return Some(name.to_string());
+    }
+    let c = OutputChar::from(key).to_string();
+    if c.chars().count() == 1 {
+        Some(c)
+    } else {
+        None
+    }
+}
+
+/// Convert a CSV chord library, with rows of the chord input, like `a + d + e`, and its output.
+/// A header row is skipped. Like on the chorder, a space is typed after each output.
+pub fn import_csv(text: &str) -> Result<Conversion> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut conversion = Conversion::default();
+    for (index, record) in reader.records().enumerate() {
+        let record = record.context("Failed to parse CSV")?;
+        let location = Location {
+            file: None,
+            line: record
+                .position()
+                .map_or(0, |position| position.line() as usize),
+            column: 1,
+        };
+        let (input, output) = match (record.get(0), record.get(1)) {
+            (Some(input), Some(output)) => (input, output),
+            _ => bail!("{}: expected a chord input and its output", location),
+        };
+        if index == 0 && input.trim().eq_ignore_ascii_case("input") {
+            continue;
+        }
+        match import_row(input, output) {
+            Ok(chord) => conversion.entries.push(MappingEntry {
+                chord,
+                output: escape(output),
+                alternatives: Vec::new(),
+                ordered: false,
+                space: Space::After,
+                case: Case::Keep,
+                comment: None,
+                tags: Vec::new(),
+                location,
+            }),
+            Err(err) => conversion.skipped.push(Skipped {
+                location,
+                entry: format!("{},{}", input, output),
+                reason: format!("{:#}", err),
+            }),
+        }
+    }
+    Ok(conversion)
+}
+
+fn import_row(input: &str, output: &str) -> Result<String> {
+    if output.is_empty() {
+        bail!("Empty output");
+    }
+    let keys = input
+        .split(" + ")
+        .map(|action| parse_action(action.trim()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(keys.iter().map(|key| key.as_string()).collect())
+}
+
+/// Write the entries as a CSV chord library, in the order they are given in.
+/// When several entries have the same chord, only the one that replaces the others is written.
+/// Entries that the chorder can't type the same way, like ordered chords, key combinations
+/// or outputs followed by neither a space nor an automatic one, are skipped.
+pub fn export_csv(
+    entries: &[MappingEntry],
+    chord_layout: Option<&Layout>,
+) -> Result<(String, Vec<Skipped>)> {
+    let mut last_by_chord = HashMap::new();
+    for (index, entry) in entries.iter().enumerate() {
+        last_by_chord.insert(entry.parse_chord(chord_layout), index);
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut skipped = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        if last_by_chord[&entry.parse_chord(chord_layout)] != index {
+            continue;
+        }
+        match export_entry(entry, chord_layout) {
+            Ok((input, output)) => writer.write_record([input, output])?,
+            Err(err) => skipped.push(Skipped {
+                location: entry.location.clone(),
+                entry: format!("{:?}: {:?}", entry.chord, entry.output),
+                reason: format!("{:#}", err),
+            }),
+        }
+    }
+    let csv = String::from_utf8(writer.into_inner()?)?;
+    Ok((csv, skipped))
+}
+
+fn export_entry(entry: &MappingEntry, chord_layout: Option<&Layout>) -> Result<(String, String)> {
+    if entry.ordered {
+        bail!("The chorder doesn't have ordered chords");
+    }
+    if !entry.alternatives.is_empty() {
+        bail!("The chorder has a single output per chord");
+    }
+    let mut actions = Vec::new();
+    for part in split_keys(&entry.chord) {
+        let key = match parse_keys(part, chord_layout)[..] {
+            [key] if key != KeyCode::UNKNOWN => key,
+            _ => bail!("Unknown key {}", part),
+        };
+        actions.push(action_name(key).with_context(|| format!("No action key for {}", part))?);
+    }
+
+    let mut output = entry.parse_output(None)?;
+    // the chorder adds its own space after each output, which is what an automatic space does
+    if entry.space != Space::Auto && output.pop().and_then(|c| c.as_char()) != Some(' ') {
+        bail!("The chorder types a space after each output");
+    }
+    let text = output
+        .iter()
+        .map(|c| match c.as_char() {
+            Some(text) => Ok(text),
+            None => bail!("The chorder can't type {} as part of an output", c),
+        })
+        .collect::<Result<String>>()?;
+    Ok((actions.join(" + "), text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappings::{parse_entries, Format};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_import_csv() {
+        let conversion = import_csv(
+            "input,output\n\
+             a + d + e,made\n\
+             SPACE + t,<3\n\
+             ; + x,semicolon\n\
+             A + b,shifted\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ("ade", "made", 2),
+                (" t", "<lt>3", 3),
+                ("<semicolon>x", "semicolon", 4)
+            ],
+            conversion
+                .entries
+                .iter()
+                .map(|entry| (
+                    entry.chord.as_str(),
+                    entry.output.as_str(),
+                    entry.location.line
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["5:1: A + b,shifted: A can't be pressed as a single key"],
+            conversion
+                .skipped
+                .iter()
+                .map(|skipped| skipped.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let entries = parse_entries(
+            r#"{
+                "ade": { "output": "made", "space": "after" },
+                "<semicolon>x": { "output": "a, b", "space": "after" },
+                "ab": "no space",
+                "cd": { "output": "<ctrl-c>", "space": "after" },
+                "ef": { "output": "ordered", "space": "after", "ordered": true },
+                "gh": { "output": ["their", "there"], "space": "after" },
+                "eda": { "output": "replaced", "space": "after" },
+                "ij": { "output": "auto", "space": "auto" }
+            }"#,
+            Format::Json,
+        )
+        .unwrap();
+        let (csv, skipped) = export_csv(&entries, None).unwrap();
+        assert_eq!("; + x,\"a, b\"\ne + d + a,replaced\ni + j,auto\n", csv);
+        assert_eq!(
+            vec![
+                "4:17: \"ab\": \"no space\": The chorder types a space after each output",
+                "5:17: \"cd\": \"<ctrl-c>\": The chorder can't type <ctrl-c> as part of an output",
+                "6:17: \"ef\": \"ordered\": The chorder doesn't have ordered chords",
+                "7:17: \"gh\": \"their\": The chorder has a single output per chord",
+            ],
+            skipped
+                .iter()
+                .map(|skipped| skipped.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use crate::mappings::{Location, MappingEntry};
+
+pub mod charachorder;
+pub mod plover;
+
+/// An entry of another dictionary format that couldn't be converted into a mapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub location: Location,
+    /// the entry, as written in the dictionary
+    pub entry: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.entry, self.reason)
+    }
+}
+
+/// The result of converting a dictionary into mappings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conversion {
+    pub entries: Vec<MappingEntry>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl Conversion {
+    /// Record the file the dictionary was read from in the locations of its entries.
+    pub fn in_file(mut self, path: &Path) -> Self {
+        let file: Arc<Path> = Arc::from(path);
+        let locations = self.entries.iter_mut().map(|entry| &mut entry.location);
+        for location in
+            locations.chain(self.skipped.iter_mut().map(|skipped| &mut skipped.location))
+        {
+            location.file = Some(file.clone());
+        }
+        self
+    }
+}
+
+/// Escape the angle brackets of plain text, as they would otherwise start a key name.
+pub fn escape(text: &str) -> String {
+    text.replace('<', "\u{0}")
+        .replace('>', "<gt>")
+        .replace('\u{0}', "<lt>")
+}
@@ -0,0 +1,337 @@
+use anyhow::*;
+use std::collections::HashMap;
+
+use super::{escape, Conversion, Skipped};
+use crate::{
+    keyboard::output_char::output_chars_from_string,
+    mappings::{parse_entries, Format, MappingEntry, Space},
+};
+
+/// Steno keys in steno order. Keys of the left hand end in `-`, keys of the right hand start with it.
+const STENO_ORDER: &[&str] = &[
+    "#", "S-", "T-", "K-", "P-", "W-", "H-", "R-", "A-", "O-", "*", "-E", "-U", "-F", "-R", "-P",
+    "-B", "-L", "-G", "-T", "-S", "-D", "-Z",
+];
+
+/// Steno keys that are written as digits when the number bar is pressed.
+const NUMBERS: &[(char, &str)] = &[
+    ('1', "S-"),
+    ('2', "T-"),
+    ('3', "P-"),
+    ('4', "H-"),
+    ('5', "A-"),
+    ('0', "O-"),
+    ('6', "-F"),
+    ('7', "-P"),
+    ('8', "-L"),
+    ('9', "-T"),
+];
+
+/// Plover's default QWERTY layout, with the first of the keys it allows for each steno key.
+const PLOVER_QWERTY: &[(&str, &str)] = &[
+    ("#", "1"),
+    ("S-", "a"),
+    ("T-", "w"),
+    ("K-", "s"),
+    ("P-", "e"),
+    ("W-", "d"),
+    ("H-", "r"),
+    ("R-", "f"),
+    ("A-", "c"),
+    ("O-", "v"),
+    ("*", "t"),
+    ("-E", "n"),
+    ("-U", "m"),
+    ("-F", "u"),
+    ("-R", "j"),
+    ("-P", "i"),
+    ("-B", "k"),
+    ("-L", "o"),
+    ("-G", "l"),
+    ("-T", "p"),
+    ("-S", "<semicolon>"),
+    ("-D", "<leftbrace>"),
+    ("-Z", "<apostrophe>"),
+];
+
+/// The keyboard key that is pressed for each steno key, written like the keys of a chord.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StenoMap {
+    keys: HashMap<&'static str, String>,
+}
+
+impl Default for StenoMap {
+    fn default() -> Self {
+        StenoMap {
+            keys: PLOVER_QWERTY
+                .iter()
+                .map(|(steno, key)| (*steno, key.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl StenoMap {
+    /// Parse an object of steno keys and keyboard keys, like `{"S-": "q", "-Z": "<slash>"}`.
+    /// Steno keys that aren't given keep their key of Plover's QWERTY layout.
+    pub fn from_reader<R: std::io::Read>(reader: R) -> Result<Self> {
+        let keys: HashMap<String, String> =
+            serde_json::from_reader(reader).context("Failed to parse steno map")?;
+        let mut map = StenoMap::default();
+        for (steno, key) in keys {
+            let steno = STENO_ORDER
+                .iter()
+                .find(|known| **known == steno)
+                .with_context(|| format!("Unknown steno key {}", steno))?;
+            map.keys.insert(steno, key);
+        }
+        Ok(map)
+    }
+
+    /// The keys of the chord that is pressed for a stroke.
+    fn chord(&self, stroke: &[&'static str]) -> String {
+        stroke
+            .iter()
+            .map(|steno| self.keys[steno].as_str())
+            .collect()
+    }
+}
+
+/// Parse a single stroke, like `STKPW`, `-T` or `1-9`, into its steno keys.
+pub fn parse_stroke(stroke: &str) -> Result<Vec<&'static str>> {
+    let mut keys = Vec::new();
+    let mut position = 0;
+    for c in stroke.chars() {
+        if c == '-' {
+            position = position.max(STENO_ORDER.iter().position(|k| *k == "-F").unwrap());
+            continue;
+        }
+        let (name, is_number) = match NUMBERS.iter().find(|(digit, _)| *digit == c) {
+            Some((_, key)) => (key.trim_matches('-').to_owned(), true),
+            None => (c.to_string(), false),
+        };
+        let index = (position..STENO_ORDER.len())
+            .find(|i| STENO_ORDER[*i].trim_matches('-') == name)
+            .with_context(|| format!("Invalid stroke {}", stroke))?;
+        keys.push(STENO_ORDER[index]);
+        if is_number && !keys.contains(&"#") {
+            keys.insert(0, "#");
+        }
+        position = index + 1;
+    }
+    if keys.is_empty() {
+        bail!("Empty stroke");
+    }
+    Ok(keys)
+}
+
+/// Convert a Plover translation into the output of a mapping, and whether a space should follow it.
+///
+/// Like in Plover, outputs are followed by a space. Attaching to the output next to it
+/// is done with `<attach>`, which takes back the space between them.
+pub fn convert_translation(translation: &str) -> Result<(String, Space)> {
+    let pattern = regex::Regex::new(r"\{[^{}]*\}|[^{]+").unwrap();
+    let mut output = String::new();
+    // only text is followed by a space, commands like `{-|}` on their own aren't
+    let mut space = false;
+    for part in pattern.find_iter(translation).map(|part| part.as_str()) {
+        let command = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+            Some(command) => command,
+            None => {
+                output.push_str(&escape(part));
+                space = true;
+                continue;
+            }
+        };
+        match command {
+            // resets formatting, which isn't needed here
+            "" => {}
+            "-|" => output.push_str("<cap-next>"),
+            "," | ":" | ";" | "." | "?" | "!" => {
+                if output.is_empty() {
+                    output.push_str("<attach>");
+                }
+                output.push_str(command);
+                if matches!(command, "." | "?" | "!") {
+                    output.push_str("<cap-next>");
+                }
+                space = true;
+            }
+            keys if keys.starts_with('#') => {
+                for combo in keys[1..].split_whitespace() {
+                    output.push_str(&convert_key_combo(combo)?);
+                }
+                space = false;
+            }
+            text if text.starts_with('^') || text.ends_with('^') => {
+                let attached = text.strip_prefix('^').unwrap_or(text);
+                let attached = attached.strip_suffix('^').unwrap_or(attached);
+                if text.starts_with('^') && output.is_empty() {
+                    output.push_str("<attach>");
+                }
+                output.push_str(&escape(attached));
+                if text.ends_with('^') && !output.ends_with("<attach>") {
+                    output.push_str("<attach>");
+                }
+                space = !text.ends_with('^');
+            }
+            other => bail!("Unsupported command {{{}}}", other),
+        }
+    }
+    if output.is_empty() {
+        bail!("Empty translation");
+    }
+    let space = if space { Space::After } else { Space::None };
+    Ok((output, space))
+}
+
+/// Convert a key combination of a `{#...}` command, like `Control_L(BackSpace)`, into a key name like `<ctrl-backspace>`.
+fn convert_key_combo(combo: &str) -> Result<String> {
+    let mut names = Vec::new();
+    let mut rest = combo;
+    while let Some(open) = rest.find('(') {
+        names.push(modifier_name(&rest[..open])?.to_owned());
+        rest = rest[open + 1..]
+            .strip_suffix(')')
+            .with_context(|| format!("Unbalanced parentheses in {}", combo))?;
+    }
+    names.push(key_name(rest));
+    Ok(format!("<{}>", names.join("-")))
+}
+
+fn modifier_name(name: &str) -> Result<&'static str> {
+    Ok(match name.to_lowercase().as_str() {
+        "control" | "control_l" | "control_r" => "ctrl",
+        "shift" | "shift_l" | "shift_r" => "shift",
+        "alt" | "alt_l" | "alt_r" => "alt",
+        "super" | "super_l" | "super_r" => "meta",
+        _ => bail!("Unsupported modifier {}", name),
+    })
+}
+
+fn key_name(name: &str) -> String {
+    match name.to_lowercase().as_str() {
+        "return" => "enter".to_owned(),
+        "escape" => "esc".to_owned(),
+        "prior" | "page_up" => "pageup".to_owned(),
+        "next" | "page_down" => "pagedown".to_owned(),
+        "minus" => "-".to_owned(),
+        other => other.to_owned(),
+    }
+}
+
+/// Convert a Plover dictionary, which maps strokes like `STKPW` to their translation,
+/// into mappings that press the keys of each stroke on a keyboard, as given by the steno map.
+/// Entries that need several strokes or use unsupported commands are skipped.
+pub fn convert_dictionary(text: &str, steno_map: &StenoMap) -> Result<Conversion> {
+    let mut conversion = Conversion::default();
+    for entry in parse_entries(text, Format::Json)? {
+        let converted = convert_entry(&entry.chord, &entry.output, steno_map);
+        match converted {
+            Ok((chord, output, space)) => conversion.entries.push(MappingEntry {
+                chord,
+                output,
+                space,
+                ..entry
+            }),
+            Err(err) => conversion.skipped.push(Skipped {
+                location: entry.location,
+                entry: format!("{:?}: {:?}", entry.chord, entry.output),
+                reason: format!("{:#}", err),
+            }),
+        }
+    }
+    Ok(conversion)
+}
+
+fn convert_entry(
+    strokes: &str,
+    translation: &str,
+    steno_map: &StenoMap,
+) -> Result<(String, String, Space)> {
+    if strokes.contains('/') {
+        bail!("Entries of several strokes can't be a single chord");
+    }
+    let chord = steno_map.chord(&parse_stroke(strokes)?);
+    let (output, space) = convert_translation(translation)?;
+    output_chars_from_string(&output, None)?;
+    Ok((chord, output, space))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_stroke() {
+        assert_eq!(
+            vec!["S-", "T-", "K-", "P-", "W-"],
+            parse_stroke("STKPW").unwrap()
+        );
+        assert_eq!(vec!["-T"], parse_stroke("-T").unwrap());
+        assert_eq!(vec!["T-", "-S"], parse_stroke("TS").unwrap());
+        assert_eq!(
+            vec!["K-", "A-", "*", "-T", "-S"],
+            parse_stroke("KA*TS").unwrap()
+        );
+        assert_eq!(vec!["#", "S-", "-T"], parse_stroke("1-9").unwrap());
+        assert!(parse_stroke("TK-X").is_err());
+        assert!(parse_stroke("TS-T").is_err());
+    }
+
+    #[test]
+    fn test_convert_translation() {
+        let convert = |s| convert_translation(s).unwrap();
+        assert_eq!(("the".to_owned(), Space::After), convert("the"));
+        assert_eq!(("<attach>ing".to_owned(), Space::After), convert("{^ing}"));
+        assert_eq!(("re<attach>".to_owned(), Space::None), convert("{re^}"));
+        assert_eq!(("<attach>".to_owned(), Space::None), convert("{^}"));
+        assert_eq!(("<attach>,".to_owned(), Space::After), convert("{,}"));
+        assert_eq!(
+            ("<attach>.<cap-next>".to_owned(), Space::After),
+            convert("{.}")
+        );
+        assert_eq!(("<cap-next>".to_owned(), Space::None), convert("{-|}"));
+        assert_eq!(
+            ("the<cap-next>".to_owned(), Space::After),
+            convert("the{-|}")
+        );
+        assert_eq!(
+            ("<ctrl-backspace><enter>".to_owned(), Space::None),
+            convert("{#Control_L(BackSpace) Return}")
+        );
+        assert_eq!(("<lt>3".to_owned(), Space::After), convert("<3"));
+        assert!(convert_translation("{&a}").is_err());
+    }
+
+    #[test]
+    fn test_convert_dictionary() {
+        let conversion = convert_dictionary(
+            r#"{
+                "STKPW": "z",
+                "-T": "the",
+                "TH/-T": "this that",
+                "KW-GS": "{&a}"
+            }"#,
+            &StenoMap::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![("awsed", "z"), ("p", "the")],
+            conversion
+                .entries
+                .iter()
+                .map(|entry| (entry.chord.as_str(), entry.output.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(4, "\"TH/-T\": \"this that\""), (5, "\"KW-GS\": \"{&a}\"")],
+            conversion
+                .skipped
+                .iter()
+                .map(|skipped| (skipped.location.line, skipped.entry.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::*;
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    diagnostics::Fingers,
+    dictionaries::{escape, Conversion, Skipped},
+    keyboard::{
+        chord::{parse_keys, Chord},
+        key_code::KeyCode,
+        layout::Layout,
+    },
+    mappings::{Case, Location, MappingEntry, Space},
+};
+
+/// The keys chords are made of, by their position on the keyboard, from the most to the least comfortable ones.
+const KEY_COSTS: &[&[KeyCode]] = &[
+    &[
+        KeyCode::KEY_A,
+        KeyCode::KEY_S,
+        KeyCode::KEY_D,
+        KeyCode::KEY_F,
+        KeyCode::KEY_J,
+        KeyCode::KEY_K,
+        KeyCode::KEY_L,
+        KeyCode::KEY_SEMICOLON,
+    ],
+    &[
+        KeyCode::KEY_W,
+        KeyCode::KEY_E,
+        KeyCode::KEY_R,
+        KeyCode::KEY_U,
+        KeyCode::KEY_I,
+        KeyCode::KEY_O,
+    ],
+    &[
+        KeyCode::KEY_G,
+        KeyCode::KEY_H,
+        KeyCode::KEY_Q,
+        KeyCode::KEY_P,
+        KeyCode::KEY_T,
+        KeyCode::KEY_Y,
+    ],
+    &[
+        KeyCode::KEY_X,
+        KeyCode::KEY_C,
+        KeyCode::KEY_V,
+        KeyCode::KEY_M,
+        KeyCode::KEY_COMMA,
+        KeyCode::KEY_DOT,
+    ],
+    &[
+        KeyCode::KEY_Z,
+        KeyCode::KEY_B,
+        KeyCode::KEY_N,
+        KeyCode::KEY_SLASH,
+    ],
+];
+
+/// Extra cost of each key beyond two, so short chords are preferred.
+const EXTRA_KEY_COST: usize = 2;
+/// Extra cost of chords without the first letter of their word, which makes them harder to remember.
+const MISSING_FIRST_LETTER_COST: usize = 3;
+
+fn key_cost(key: KeyCode) -> Option<usize> {
+    KEY_COSTS.iter().position(|keys| keys.contains(&key))
+}
+
+/// The key that types a letter, which is the key of its name on a US layout if there is no layout.
+fn letter_key(c: char, chord_layout: Option<&Layout>) -> KeyCode {
+    parse_keys(&c.to_string(), chord_layout)[0]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerateConfig {
+    /// the most keys a generated chord may have
+    pub max_keys: usize,
+    /// the most times the keys of a chord may be typed as consecutive letters of a word in the corpus
+    pub max_rolls: usize,
+}
+
+impl Default for GenerateConfig {
+    fn default() -> Self {
+        GenerateConfig {
+            max_keys: 4,
+            max_rolls: 2,
+        }
+    }
+}
+
+/// How often each set of keys is typed as consecutive letters of a word in a text.
+/// When typing quickly, those keys are held down together, so a chord of them would be typed by accident.
+#[derive(Debug, Default)]
+pub struct Rolls {
+    counts: HashMap<Chord, usize>,
+}
+
+impl Rolls {
+    pub fn from_text(text: &str, max_keys: usize, chord_layout: Option<&Layout>) -> Self {
+        let mut counts = HashMap::new();
+        for word in text.split(|c: char| !c.is_alphabetic()) {
+            let keys = word
+                .to_lowercase()
+                .chars()
+                .map(|c| letter_key(c, chord_layout))
+                .collect::<Vec<_>>();
+            for len in 2..=max_keys {
+                for window in keys.windows(len) {
+                    *counts
+                        .entry(Chord::from_key_codes(window.to_vec()))
+                        .or_insert(0) += 1;
+                }
+            }
+        }
+        Rolls { counts }
+    }
+
+    pub fn count(&self, chord: &Chord) -> usize {
+        self.counts.get(chord).copied().unwrap_or(0)
+    }
+}
+
+/// A chord made of letters of a word.
+struct Candidate {
+    letters: String,
+    keys: Vec<KeyCode>,
+    cost: usize,
+}
+
+/// All chords of up to `max_keys` letters of the word, the most comfortable ones first.
+fn candidates(word: &str, chord_layout: Option<&Layout>, max_keys: usize) -> Vec<Candidate> {
+    let mut letters: Vec<(char, KeyCode)> = Vec::new();
+    for c in word.to_lowercase().chars().filter(|c| c.is_alphabetic()) {
+        let key = letter_key(c, chord_layout);
+        if key_cost(key).is_some() && letters.iter().all(|(_, k)| *k != key) {
+            letters.push((c, key));
+        }
+    }
+    let first = letters.first().map(|(_, key)| *key);
+
+    (2..=max_keys.min(letters.len()))
+        .flat_map(|len| letters.iter().combinations(len))
+        .map(|combination| {
+            let keys = combination.iter().map(|(_, key)| *key).collect::<Vec<_>>();
+            let mut cost = keys.iter().filter_map(|key| key_cost(*key)).sum::<usize>()
+                + (keys.len() - 2) * EXTRA_KEY_COST;
+            if !first.is_some_and(|first| keys.contains(&first)) {
+                cost += MISSING_FIRST_LETTER_COST;
+            }
+            Candidate {
+                letters: combination.iter().map(|(c, _)| *c).collect(),
+                keys,
+                cost,
+            }
+        })
+        .sorted_by_key(|candidate| candidate.cost)
+        .collect()
+}
+
+/// Parse a word list with a word and, optionally, its frequency on each line, like `the 23135851162`.
+/// Lines starting with `#` are comments. The words are returned with the most frequent ones first.
+fn parse_word_list(text: &str) -> Result<Vec<(Location, String)>> {
+    let mut words = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let mut parts = line.split_whitespace();
+        let word = match parts.next() {
+            Some(word) if !word.starts_with('#') => word,
+            _ => continue,
+        };
+        let frequency = match parts.next() {
+            Some(frequency) => frequency
+                .parse::<f64>()
+                .with_context(|| format!("{}: invalid frequency {}", index + 1, frequency))?,
+            None => 0.0,
+        };
+        let location = Location {
+            file: None,
+            line: index + 1,
+            column: 1,
+        };
+        words.push((frequency, location, word.to_owned()));
+    }
+    words.sort_by(|(a, ..), (b, ..)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(words
+        .into_iter()
+        .map(|(_, location, word)| (location, word))
+        .collect())
+}
+
+/// Propose a chord made of letters of each word of a word list, giving the most frequent words the most comfortable chords.
+/// Chords of the existing entries are left alone, as are words they already type.
+/// If `rolls` are given, chords whose keys are often typed one after the other are avoided,
+/// and if `fingers` are given, so are chords that need one finger to press several keys.
+pub fn generate(
+    words: &str,
+    existing: &[MappingEntry],
+    rolls: Option<&Rolls>,
+    chord_layout: Option<&Layout>,
+    fingers: Option<&Fingers>,
+    config: GenerateConfig,
+) -> Result<Conversion> {
+    let mut taken: HashSet<Chord> = existing
+        .iter()
+        .map(|entry| entry.parse_chord(chord_layout).unordered())
+        .collect();
+    let mapped: HashSet<String> = existing
+        .iter()
+        .flat_map(|entry| Some(&entry.output).into_iter().chain(&entry.alternatives))
+        .map(|output| output.to_lowercase())
+        .collect();
+
+    let mut conversion = Conversion::default();
+    for (location, word) in parse_word_list(words)? {
+        if mapped.contains(&word.to_lowercase()) {
+            conversion.skipped.push(Skipped {
+                location,
+                entry: word,
+                reason: "Already typed by an existing chord".to_owned(),
+            });
+            continue;
+        }
+        let chosen = candidates(&word, chord_layout, config.max_keys)
+            .into_iter()
+            .map(|candidate| {
+                let chord = Chord::from_key_codes(candidate.keys.clone());
+                (candidate, chord)
+            })
+            .find(|(candidate, chord)| {
+                !taken.contains(chord)
+                    && rolls.is_none_or(|rolls| rolls.count(chord) <= config.max_rolls)
+                    && fingers.is_none_or(|fingers| {
+                        let used = candidate
+                            .keys
+                            .iter()
+                            .filter_map(|key| fingers.finger(key))
+                            .collect::<Vec<_>>();
+                        used.iter().unique().count() == used.len()
+                    })
+            });
+        match chosen {
+            Some((candidate, chord)) => {
+                taken.insert(chord);
+                conversion.entries.push(MappingEntry {
+                    chord: candidate.letters,
+                    output: escape(&word),
+                    alternatives: Vec::new(),
+                    ordered: false,
+                    space: Space::After,
+                    case: Case::Keep,
+                    comment: None,
+                    tags: Vec::new(),
+                    location,
+                });
+            }
+            None => conversion.skipped.push(Skipped {
+                location,
+                entry: word,
+                reason: "No free chord of its letters".to_owned(),
+            }),
+        }
+    }
+    Ok(conversion)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappings::{parse_entries, Format};
+    use pretty_assertions::assert_eq;
+
+    fn chords(conversion: &Conversion) -> Vec<(&str, &str)> {
+        conversion
+            .entries
+            .iter()
+            .map(|entry| (entry.output.as_str(), entry.chord.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_generate() {
+        let words = "# word frequency\nsad 10\nsaid 30\nas 20\nthe 5\nfoo\n";
+        let existing = parse_entries(r#"{ "ad": "add", "th": "the" }"#, Format::Json).unwrap();
+        let conversion = generate(
+            words,
+            &existing,
+            None,
+            None,
+            None,
+            GenerateConfig::default(),
+        )
+        .unwrap();
+        // "as" is the same chord as the one of the more frequent "said"
+        assert_eq!(
+            vec![("said", "sa"), ("sad", "sd"), ("foo", "fo")],
+            chords(&conversion)
+        );
+        assert_eq!(
+            vec![
+                "4:1: as: No free chord of its letters",
+                "5:1: the: Already typed by an existing chord"
+            ],
+            conversion
+                .skipped
+                .iter()
+                .map(|skipped| skipped.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_generate_avoids_rolls_and_same_finger() {
+        let rolls = Rolls::from_text("Sad, sad days.", 4, None);
+        let fingers = Fingers::from_reader(r#"{ "left middle": "edc" }"#.as_bytes(), None).unwrap();
+        let conversion = generate(
+            "said\ndee\n",
+            &[],
+            Some(&rolls),
+            None,
+            Some(&fingers),
+            GenerateConfig {
+                max_rolls: 0,
+                ..GenerateConfig::default()
+            },
+        )
+        .unwrap();
+        // "sa" is a roll, and "dee" only has keys of the same finger
+        assert_eq!(vec![("said", "sd")], chords(&conversion));
+        assert_eq!(2, conversion.skipped[0].location.line);
+    }
+}
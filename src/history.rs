@@ -29,6 +29,10 @@ impl<T> HistoryList<T> {
     }
 
     pub fn newest(&self) -> Option<&T> {
-        self.data.get(0)
+        self.data.front()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
     }
 }
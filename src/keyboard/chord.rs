@@ -1,55 +1,130 @@
 use itertools::Itertools;
 
-use crate::keyboard::key_code::KeyCode;
+use crate::keyboard::{key_code::KeyCode, layout::Layout};
 
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub struct Chord(Vec<KeyCode>);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Chord {
+    keys: Vec<KeyCode>,
+    /// whether the order in which the keys are pressed matters
+    ordered: bool,
+}
 
 impl Chord {
     pub fn from_string<S: AsRef<str>>(s: S) -> Self {
-        let pattern = regex::Regex::new("<.*?>|.").unwrap();
-        Chord(
-            pattern
-                .find_iter(s.as_ref())
-                .map(|part| part.as_str().to_owned().parse().unwrap_or(KeyCode::UNKNOWN))
-                .sorted()
-                .dedup()
-                .collect(),
-        )
+        Chord::from_key_codes(parse_keys(s.as_ref(), None))
+    }
+
+    /// Parse a chord whose keys need to be pressed in the given order.
+    pub fn ordered_from_string<S: AsRef<str>>(s: S) -> Self {
+        Chord::ordered_from_key_codes(parse_keys(s.as_ref(), None))
     }
 
     pub fn len(&self) -> usize {
-        self.0.iter().filter(|x| !x.is_control()).count()
+        self.keys.iter().filter(|x| !x.is_control()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_ordered(&self) -> bool {
+        self.ordered
+    }
+
+    /// The same chord, but without caring about the order of the keys.
+    pub fn unordered(&self) -> Chord {
+        Chord::from_key_codes(self.keys.clone())
     }
 }
 
 impl Chord {
     pub fn from_key_codes(keys: Vec<KeyCode>) -> Self {
         let parts: Vec<KeyCode> = keys.into_iter().sorted().dedup().collect_vec();
-        Chord(parts)
+        Chord {
+            keys: parts,
+            ordered: false,
+        }
+    }
+
+    /// Create a chord from keys in the order they were pressed in.
+    pub fn ordered_from_key_codes(keys: Vec<KeyCode>) -> Self {
+        Chord {
+            keys: keys.into_iter().unique().collect(),
+            ordered: true,
+        }
     }
 }
 
+/// Split a chord into the parts naming each of its keys, like `a` or `<backspace>`.
+pub fn split_keys(s: &str) -> Vec<&str> {
+    let pattern = regex::Regex::new("<.*?>|.").unwrap();
+    pattern.find_iter(s).map(|part| part.as_str()).collect()
+}
+
+/// Parse the keys of a chord. Keys can be given by name in angle brackets, like `<backspace>`.
+/// Other characters are the keys that type them on the given layout, or their names on a US layout if there is none.
+pub fn parse_keys(s: &str, layout: Option<&Layout>) -> Vec<KeyCode> {
+    split_keys(s)
+        .into_iter()
+        .map(|part| match layout {
+            Some(layout) if !part.starts_with('<') || part == "<" => layout
+                .key(part.chars().next().unwrap())
+                .unwrap_or(KeyCode::UNKNOWN),
+            _ => part.parse().unwrap_or(KeyCode::UNKNOWN),
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    fn unordered(keys: Vec<KeyCode>) -> Chord {
+        Chord {
+            keys,
+            ordered: false,
+        }
+    }
+
     #[test]
     pub fn test_new_chord() {
         assert_eq!(
             Chord::from_string("<backspace>"),
-            Chord(vec![KeyCode::KEY_BACKSPACE])
+            unordered(vec![KeyCode::KEY_BACKSPACE])
+        );
+        assert_eq!(Chord::from_string("a"), unordered(vec![KeyCode::KEY_A]));
+        assert_eq!(
+            Chord::from_string("<pause>"),
+            unordered(vec![KeyCode::KEY_PAUSE])
         );
-        assert_eq!(Chord::from_string("a"), Chord(vec![KeyCode::KEY_A]));
         assert_eq!(
             Chord::from_string("aa<backspace>a"),
-            Chord(vec![KeyCode::KEY_A, KeyCode::KEY_BACKSPACE])
+            unordered(vec![KeyCode::KEY_A, KeyCode::KEY_BACKSPACE])
         );
 
         assert_eq!(
             Chord::from_string("a b"),
-            Chord(vec![KeyCode::KEY_A, KeyCode::KEY_B, KeyCode::KEY_SPACE])
+            unordered(vec![KeyCode::KEY_A, KeyCode::KEY_B, KeyCode::KEY_SPACE])
+        );
+    }
+
+    #[test]
+    pub fn test_ordered_chord() {
+        assert_eq!(
+            Chord::ordered_from_string("bab"),
+            Chord {
+                keys: vec![KeyCode::KEY_B, KeyCode::KEY_A],
+                ordered: true
+            }
+        );
+        assert_ne!(
+            Chord::ordered_from_string("ab"),
+            Chord::ordered_from_string("ba")
+        );
+        assert_eq!(
+            Chord::ordered_from_string("ba").unordered(),
+            Chord::from_string("ab")
         );
     }
 }
@@ -0,0 +1,217 @@
+use anyhow::*;
+use evdev_rs::{
+    enums::{EventCode, EventType, EV_KEY},
+    Device,
+};
+use itertools::Itertools;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub(crate) const INPUT_DIR: &str = "/dev/input";
+pub(crate) const BY_ID_DIR: &str = "/dev/input/by-id";
+
+/// Describes which input device to use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// path to a device node, such as `/dev/input/event3` or a symlink in `/dev/input/by-id`
+    Path(PathBuf),
+    /// vendor and product id, written as `vendor:product` in hex, like `046d:c52b`
+    Id { vendor: u16, product: u16 },
+    /// name of the device, as reported by the kernel
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let id_pattern = regex::Regex::new("^([0-9a-fA-F]{4}):([0-9a-fA-F]{4})$").unwrap();
+        if s.starts_with('/') {
+            Ok(DeviceSelector::Path(PathBuf::from(s)))
+        } else if let Some(captures) = id_pattern.captures(s) {
+            Ok(DeviceSelector::Id {
+                vendor: u16::from_str_radix(&captures[1], 16)?,
+                product: u16::from_str_radix(&captures[2], 16)?,
+            })
+        } else if s.is_empty() {
+            bail!("Empty device selector")
+        } else {
+            Ok(DeviceSelector::Name(s.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceSelector::Path(path) => write!(f, "{}", path.display()),
+            DeviceSelector::Id { vendor, product } => write!(f, "{:04x}:{:04x}", vendor, product),
+            DeviceSelector::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::Path(path) => {
+                info.path == *path
+                    || info.by_id.contains(path)
+                    || path.canonicalize().is_ok_and(|path| info.path == path)
+            }
+            DeviceSelector::Id { vendor, product } => {
+                info.vendor_id == *vendor && info.product_id == *product
+            }
+            DeviceSelector::Name(name) => info.name == *name,
+        }
+    }
+
+    /// Whether the given device should be used for this selector.
+    /// Paths select any device, all other selectors only select keyboards.
+    pub fn selects(&self, info: &DeviceInfo) -> bool {
+        self.matches(info) && (info.is_keyboard || matches!(self, DeviceSelector::Path(_)))
+    }
+
+    /// Find the device this selector refers to.
+    /// Paths are used as given, all other selectors only consider keyboards.
+    pub fn find(&self) -> Result<DeviceInfo> {
+        match self {
+            DeviceSelector::Path(path) => DeviceInfo::open(path),
+            _ => list_keyboards()?
+                .into_iter()
+                .find(|info| self.matches(info))
+                .with_context(|| format!("No keyboard matching '{}' found", self)),
+        }
+    }
+}
+
+/// Information about an evdev device node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// symlinks in `/dev/input/by-id` that point to this device
+    pub by_id: Vec<PathBuf>,
+    /// event types the device supports
+    pub capabilities: Vec<EventType>,
+    pub is_keyboard: bool,
+}
+
+impl DeviceInfo {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path
+            .as_ref()
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", path.as_ref().display()))?;
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let device = Device::new_from_fd(file)
+            .with_context(|| format!("{} is not an evdev device", path.display()))?;
+
+        let is_keyboard = [EV_KEY::KEY_A, EV_KEY::KEY_Z, EV_KEY::KEY_SPACE]
+            .iter()
+            .all(|key| device.has(&EventCode::EV_KEY(key.clone())));
+
+        Ok(DeviceInfo {
+            name: device.name().unwrap_or_default().to_owned(),
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            by_id: by_id_links(&path),
+            capabilities: EventType::EV_SYN
+                .iter()
+                .filter(|event_type| device.has(event_type))
+                .collect(),
+            is_keyboard,
+            path,
+        })
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} ({:04x}:{:04x})",
+            self.path.display(),
+            self.name,
+            self.vendor_id,
+            self.product_id
+        )?;
+        for link in &self.by_id {
+            writeln!(f, "  {}", link.display())?;
+        }
+        write!(
+            f,
+            "  capabilities: {}",
+            self.capabilities.iter().map(|c| c.to_string()).join(", ")
+        )
+    }
+}
+
+/// List all evdev devices that can be used as keyboards, ordered by their event number.
+pub fn list_keyboards() -> Result<Vec<DeviceInfo>> {
+    let mut paths = std::fs::read_dir(INPUT_DIR)
+        .with_context(|| format!("Failed to read {}", INPUT_DIR))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| event_number(path).is_some())
+        .collect_vec();
+    paths.sort_by_key(|path| event_number(path));
+
+    Ok(paths
+        .iter()
+        .filter_map(|path| DeviceInfo::open(path).ok())
+        .filter(|info| info.is_keyboard)
+        .collect())
+}
+
+/// number `N` of a `/dev/input/eventN` path.
+pub fn event_number(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("event")?
+        .parse()
+        .ok()
+}
+
+fn by_id_links(path: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(BY_ID_DIR)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|link| link.canonicalize().is_ok_and(|target| target == path))
+        .sorted()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_selector() {
+        assert_eq!(
+            DeviceSelector::Path(PathBuf::from("/dev/input/by-id/usb-kbd-event-kbd")),
+            "/dev/input/by-id/usb-kbd-event-kbd".parse().unwrap()
+        );
+        assert_eq!(
+            DeviceSelector::Id {
+                vendor: 0x046d,
+                product: 0xc52b
+            },
+            "046d:c52b".parse().unwrap()
+        );
+        assert_eq!(
+            DeviceSelector::Name("AT Translated Set 2 keyboard".to_owned()),
+            "AT Translated Set 2 keyboard".parse().unwrap()
+        );
+        assert!("".parse::<DeviceSelector>().is_err());
+    }
+}
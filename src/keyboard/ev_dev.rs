@@ -1,73 +1,404 @@
 use anyhow::*;
 use evdev_rs::{
-    enums::EventCode, enums::EV_KEY, enums::EV_SYN, Device, InputEvent, TimeVal, UInputDevice,
+    enums::int_to_ev_key, enums::EventCode, enums::EventType, enums::EV_KEY, enums::EV_REL,
+    enums::EV_SYN, Device, GrabMode, InputEvent, ReadFlag, ReadStatus, TimeVal, UInputDevice,
+};
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use itertools::Itertools;
+use std::{
+    cell::{Cell, RefCell},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd, io::RawFd},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use super::{key_code::KeyCode, Backend, KeyEvent};
+use super::{
+    discovery::{event_number, DeviceInfo, DeviceSelector, BY_ID_DIR, INPUT_DIR},
+    key_code::KeyCode,
+    Backend, KeyEvent, TimedKeyEvent,
+};
 
-pub struct EvDevBackend {
-    input_device: UInputDevice,
+/// A physical device the backend reads events from.
+struct InputDevice {
+    id: usize,
+    path: PathBuf,
+    fd: RawFd,
     device: Device,
+    /// keys that are currently held down on this device
+    held_keys: Vec<EV_KEY>,
+    /// time of the most recent event of this device
+    last_event_time: Duration,
+    /// whether pointer motion was passed on since the last `SYN_REPORT`
+    motion_pending: bool,
+}
+
+/// Watches `/dev/input` for devices that should be (re-)attached.
+struct Hotplug {
+    inotify: Inotify,
+    by_id_watch: Option<WatchDescriptor>,
+    selectors: Vec<DeviceSelector>,
+}
+
+pub struct EvDevBackend {
+    devices: RefCell<Vec<InputDevice>>,
+    /// virtual keyboard all output, as well as all passed through events, get written to.
+    output_device: UInputDevice,
+    exclusive: bool,
+    next_device_id: Cell<usize>,
+    hotplug: Option<RefCell<Hotplug>>,
 }
 
 impl EvDevBackend {
-    pub fn new(input_device: UInputDevice, device: Device) -> Self {
-        EvDevBackend {
-            input_device,
-            device,
+    /// Create a backend without any devices.
+    /// If `exclusive` is set, every device that gets added will be grabbed,
+    /// such that no other application receives its events directly.
+    pub fn new(exclusive: bool) -> Result<Self> {
+        Ok(EvDevBackend {
+            devices: RefCell::new(Vec::new()),
+            output_device: create_virtual_keyboard()?,
+            exclusive,
+            next_device_id: Cell::new(0),
+            hotplug: None,
+        })
+    }
+
+    /// Start reading events from the device at the given path.
+    /// Returns the id that events of this device will carry.
+    pub fn add_device<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let fd = file.as_raw_fd();
+
+        let mut device = Device::new().context("Error getting device")?;
+        device.set_fd(file)?;
+        if self.exclusive {
+            // absolute axes need their ranges copied to pass them on, so touchpads, tablets and the like are left alone
+            if device.has(&EventType::EV_ABS) {
+                bail!(
+                    "Refusing to grab {}, as it has absolute axes that can't be passed on",
+                    path.display()
+                );
+            }
+            device
+                .grab(GrabMode::Grab)
+                .with_context(|| format!("Failed to grab {}", path.display()))?;
         }
+
+        let id = self.next_device_id.get();
+        self.next_device_id.set(id + 1);
+        self.devices.borrow_mut().push(InputDevice {
+            id,
+            path: path.to_owned(),
+            fd,
+            device,
+            held_keys: Vec::new(),
+            last_event_time: Duration::default(),
+            motion_pending: false,
+        });
+        Ok(id)
     }
-}
 
-impl Backend for EvDevBackend {
-    fn handle_events<F: FnMut(super::KeyEvent)>(&self, mut f: F) -> Result<()> {
-        loop {
-            let event = self
-                .device
-                .next_event(evdev_rs::ReadFlag::NORMAL | evdev_rs::ReadFlag::BLOCKING);
+    /// Watch for new devices, and add every device that is selected by any of the given selectors.
+    /// Devices that are removed are always dropped, so this allows them to be reattached.
+    pub fn enable_hotplug(&mut self, selectors: Vec<DeviceSelector>) -> Result<()> {
+        let mut inotify = Inotify::init().context("Failed to initialize inotify")?;
+        inotify
+            .add_watch(INPUT_DIR, WatchMask::CREATE | WatchMask::ATTRIB)
+            .with_context(|| format!("Failed to watch {}", INPUT_DIR))?;
+        // by-id only exists once there is a device it can list
+        let by_id_watch = inotify.add_watch(BY_ID_DIR, WatchMask::CREATE).ok();
+        self.hotplug = Some(RefCell::new(Hotplug {
+            inotify,
+            by_id_watch,
+            selectors,
+        }));
+        Ok(())
+    }
+
+    /// Read all pending events of the device with the given id.
+    /// If the device is gone, or can't be read anymore, it gets removed, and all keys that were held down on it are released.
+    fn read_events<F: FnMut(TimedKeyEvent)>(&self, id: usize, f: &mut F) -> Result<()> {
+        let mut devices = self.devices.borrow_mut();
+        let index = match devices.iter().position(|input| input.id == id) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
 
-            match event {
-                Ok((_, event)) => match (event.value, event.event_code) {
-                    (0, EventCode::EV_KEY(code)) => f(KeyEvent::KeyUp(code.into())),
-                    (_, EventCode::EV_KEY(code)) => f(KeyEvent::KeyDown(code.into())),
+        let mut flag = ReadFlag::NORMAL;
+        loop {
+            let input = &mut devices[index];
+            match input.device.next_event(flag) {
+                // events were dropped, so the changes since then are read in sync mode, to not miss any releases
+                Ok((ReadStatus::Sync, _)) if flag == ReadFlag::NORMAL => flag = ReadFlag::SYNC,
+                Ok((_, event)) => match event.event_code {
+                    EventCode::EV_KEY(code) => {
+                        input.last_event_time = Duration::new(
+                            event.time.tv_sec as u64,
+                            event.time.tv_usec as u32 * 1000,
+                        );
+                        if event.value == 0 {
+                            input.held_keys.retain(|held| *held != code);
+                        } else if !input.held_keys.contains(&code) {
+                            input.held_keys.push(code.clone());
+                        }
+                        self.handle_key(input, code, event.value, f)?;
+                    }
+                    // pointer motion doesn't affect chords, so it can be passed on right away
+                    EventCode::EV_REL(_) if self.exclusive => {
+                        self.write_raw_event(&event.event_code, event.value)?;
+                        input.motion_pending = true;
+                    }
+                    EventCode::EV_SYN(EV_SYN::SYN_REPORT) if input.motion_pending => {
+                        self.write_raw_event(&event.event_code, 0)?;
+                        input.motion_pending = false;
+                    }
                     _ => {}
                 },
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if flag == ReadFlag::NORMAL {
+                        return Ok(());
+                    }
+                    flag = ReadFlag::NORMAL;
+                }
+                // a device that can't be read would keep waking up `poll` right away
+                Err(err) => {
+                    let mut input = devices.remove(index);
+                    if err.raw_os_error() == Some(libc::ENODEV) {
+                        eprintln!("Device {} was removed", input.path.display());
+                    } else {
+                        eprintln!(
+                            "Dropping {} after failing to read from it: {:#?}",
+                            input.path.display(),
+                            err
+                        );
+                    }
+                    for code in std::mem::take(&mut input.held_keys) {
+                        self.handle_key(&input, code, 0, f)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
 
-                Err(err) => eprintln!("Error while handling key event: {:#?}", err),
+    fn handle_key<F: FnMut(TimedKeyEvent)>(
+        &self,
+        input: &InputDevice,
+        code: EV_KEY,
+        value: i32,
+        f: &mut F,
+    ) -> Result<()> {
+        let time = input.last_event_time;
+        match (value, KeyCode::from(code.clone())) {
+            // keys we don't know can't be part of a chord, but they still need to come after the keys before them
+            (value, KeyCode::UNKNOWN) => f(TimedKeyEvent {
+                event: KeyEvent::Opaque {
+                    code: code as u16,
+                    value,
+                },
+                time,
+                device: input.id,
+            }),
+            (0, code) => f(TimedKeyEvent {
+                event: KeyEvent::KeyUp(code),
+                time,
+                device: input.id,
+            }),
+            (2, code) => f(TimedKeyEvent {
+                event: KeyEvent::Repeat(code),
+                time,
+                device: input.id,
+            }),
+            (_, code) => f(TimedKeyEvent {
+                event: KeyEvent::KeyDown(code),
+                time,
+                device: input.id,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Handle pending inotify events, adding every new device that is selected.
+    fn handle_hotplug(&self, hotplug: &RefCell<Hotplug>) -> Result<()> {
+        let mut buffer = [0; 4096];
+        let mut new_paths = Vec::new();
+        {
+            let mut hotplug = hotplug.borrow_mut();
+            let Hotplug {
+                inotify,
+                by_id_watch,
+                ..
+            } = &mut *hotplug;
+            let events = inotify
+                .read_events(&mut buffer)
+                .context("Failed to read inotify events")?;
+            let mut watch_by_id = false;
+            for event in events {
+                let name = match event.name {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if Some(&event.wd) == by_id_watch.as_ref() {
+                    new_paths.push(Path::new(BY_ID_DIR).join(name));
+                } else if name == "by-id" {
+                    watch_by_id = true;
+                } else {
+                    new_paths.push(Path::new(INPUT_DIR).join(name));
+                }
+            }
+            if watch_by_id && by_id_watch.is_none() {
+                *by_id_watch = inotify.add_watch(BY_ID_DIR, WatchMask::CREATE).ok();
             }
         }
+
+        let hotplug = hotplug.borrow();
+        for path in new_paths {
+            let path = match path.canonicalize().ok() {
+                Some(path) if event_number(&path).is_some() => path,
+                _ => continue,
+            };
+            if self.devices.borrow().iter().any(|input| input.path == path) {
+                continue;
+            }
+            // the device might not be accessible yet, in which case we'll get another chance once its attributes change
+            let info = match DeviceInfo::open(&path).ok() {
+                Some(info) => info,
+                None => continue,
+            };
+            if hotplug
+                .selectors
+                .iter()
+                .any(|selector| selector.selects(&info))
+            {
+                match self.add_device(&path) {
+                    Err(err) => eprintln!("Failed to attach {}: {:#?}", path.display(), err),
+                    _ => println!("Attached {}", info),
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn send_key_event(&self, event: super::KeyEvent) -> Result<()> {
-        let (key_code, state) = match event {
-            KeyEvent::KeyUp(code) => (code, 0),
-            KeyEvent::KeyDown(code) => (code, 1),
-        };
+    /// Write an event to the `output_device`, followed by a `SYN_REPORT`.
+    fn write_event(&self, event_code: &EventCode, value: i32) -> Result<()> {
+        self.write_raw_event(event_code, value)?;
+        self.write_raw_event(&EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0)
+    }
 
+    /// Write a single event to the `output_device`.
+    fn write_raw_event(&self, event_code: &EventCode, value: i32) -> Result<()> {
         let now_millis = std::time::SystemTime::now()
             .duration_since(std::time::SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis() as i64;
 
-        self.input_device
-            .write_event(&InputEvent::new(
-                &TimeVal::new(now_millis, 0),
-                &EventCode::EV_KEY(key_code.into()),
-                state,
-            ))
-            .unwrap();
-        self.input_device.write_event(&InputEvent::new(
-            &TimeVal::new(now_millis, 5),
-            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-            0,
+        self.output_device.write_event(&InputEvent::new(
+            &TimeVal::new(now_millis, 0),
+            event_code,
+            value,
         ))?;
         Ok(())
     }
 }
 
-impl Into<EV_KEY> for KeyCode {
-    fn into(self) -> EV_KEY {
-        match self {
+impl Backend for EvDevBackend {
+    fn handle_events<F: FnMut(TimedKeyEvent)>(&self, mut f: F) -> Result<()> {
+        loop {
+            let device_ids = self
+                .devices
+                .borrow()
+                .iter()
+                .map(|input| input.id)
+                .collect_vec();
+            if device_ids.is_empty() && self.hotplug.is_none() {
+                bail!("No input devices left");
+            }
+
+            let mut poll_fds = self
+                .devices
+                .borrow()
+                .iter()
+                .map(|input| input.fd)
+                .chain(self.hotplug.iter().map(|h| h.borrow().inotify.as_raw_fd()))
+                .map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect_vec();
+
+            let result =
+                unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1) };
+            if result < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err).context("Failed to poll the input devices");
+            }
+
+            for (id, poll_fd) in device_ids.iter().zip(&poll_fds) {
+                if poll_fd.revents != 0 {
+                    self.read_events(*id, &mut f)?;
+                }
+            }
+            if let Some(hotplug) = &self.hotplug {
+                if poll_fds.last().is_some_and(|poll_fd| poll_fd.revents != 0) {
+                    self.handle_hotplug(hotplug)?;
+                }
+            }
+        }
+    }
+
+    fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    fn send_key_event(&self, event: super::KeyEvent) -> Result<()> {
+        let (key_code, state) = match event {
+            KeyEvent::KeyUp(code) => (code.into(), 0),
+            KeyEvent::KeyDown(code) => (code.into(), 1),
+            KeyEvent::Repeat(code) => (code.into(), 2),
+            KeyEvent::Opaque { code, value } => (
+                int_to_ev_key(code.into()).with_context(|| format!("Unknown key code {}", code))?,
+                value,
+            ),
+        };
+        self.write_event(&EventCode::EV_KEY(key_code), state)
+    }
+}
+
+/// Create a uinput device that is able to send every key,
+/// as well as the buttons and motion of a mouse, which grabbed devices might have too.
+fn create_virtual_keyboard() -> Result<UInputDevice> {
+    let device = Device::new().context("Error creating virtual keyboard")?;
+    device.set_name("chordthingy virtual keyboard");
+    device.enable(&EventType::EV_KEY)?;
+    device.enable(&EventType::EV_REL)?;
+    let mouse_buttons = EV_KEY::BTN_LEFT as u32..=EV_KEY::BTN_TASK as u32;
+    for code in EventCode::EV_KEY(EV_KEY::KEY_RESERVED).iter() {
+        match &code {
+            EventCode::EV_KEY(key)
+                if code.to_string().starts_with("KEY_")
+                    || mouse_buttons.contains(&(key.clone() as u32)) =>
+            {
+                device.enable(&code)?
+            }
+            EventCode::EV_KEY(_) | EventCode::EV_REL(EV_REL::REL_MAX) => {}
+            EventCode::EV_REL(_) => device.enable(&code)?,
+            _ => break,
+        }
+    }
+    UInputDevice::create_from_device(&device).context("Error creating virtual keyboard")
+}
+
+impl From<KeyCode> for EV_KEY {
+    fn from(k: KeyCode) -> Self {
+        match k {
             KeyCode::KEY_0 => EV_KEY::KEY_0,
             KeyCode::KEY_1 => EV_KEY::KEY_1,
             KeyCode::KEY_2 => EV_KEY::KEY_2,
@@ -173,6 +504,7 @@ impl Into<EV_KEY> for KeyCode {
             KeyCode::KEY_RIGHTBRACE => EV_KEY::KEY_RIGHTBRACE,
             KeyCode::KEY_RIGHT_DOWN => EV_KEY::KEY_RIGHT_DOWN,
             KeyCode::KEY_RIGHTSHIFT => EV_KEY::KEY_RIGHTSHIFT,
+            KeyCode::KEY_PAUSE => EV_KEY::KEY_PAUSE,
             _ => EV_KEY::KEY_UNKNOWN,
         }
     }
@@ -286,6 +618,7 @@ impl From<EV_KEY> for KeyCode {
             EV_KEY::KEY_RIGHTBRACE => KeyCode::KEY_RIGHTBRACE,
             EV_KEY::KEY_RIGHT_DOWN => KeyCode::KEY_RIGHT_DOWN,
             EV_KEY::KEY_RIGHTSHIFT => KeyCode::KEY_RIGHTSHIFT,
+            EV_KEY::KEY_PAUSE => KeyCode::KEY_PAUSE,
             _ => KeyCode::UNKNOWN,
         }
     }
@@ -110,6 +110,7 @@ pub enum KeyCode {
     KEY_RIGHT_DOWN,
     KEY_RIGHTSHIFT,
     KEY_SPACE,
+    KEY_PAUSE,
     UNKNOWN,
 }
 
@@ -221,70 +222,90 @@ impl KeyCode {
             KeyCode::KEY_RIGHTBRACE => "<rightbrace>",
             KeyCode::KEY_RIGHT_DOWN => "<right_down>",
             KeyCode::KEY_RIGHTSHIFT => "<rightshift>",
+            KeyCode::KEY_PAUSE => "<pause>",
             KeyCode::UNKNOWN => "<???>",
         }
     }
 
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self,
+            KeyCode::KEY_LEFTSHIFT
+                | KeyCode::KEY_RIGHTSHIFT
+                | KeyCode::KEY_LEFTCTRL
+                | KeyCode::KEY_RIGHTCTRL
+                | KeyCode::KEY_LEFTALT
+                | KeyCode::KEY_RIGHTALT
+                | KeyCode::KEY_LEFTMETA
+                | KeyCode::KEY_RIGHTMETA
+        )
+    }
+
+    pub fn is_shift(&self) -> bool {
+        matches!(self, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT)
+    }
+
     pub fn is_control(&self) -> bool {
-        match self {
+        matches!(
+            self,
             KeyCode::KEY_F1
-            | KeyCode::KEY_F2
-            | KeyCode::KEY_F3
-            | KeyCode::KEY_F4
-            | KeyCode::KEY_F5
-            | KeyCode::KEY_F6
-            | KeyCode::KEY_F7
-            | KeyCode::KEY_F8
-            | KeyCode::KEY_F9
-            | KeyCode::KEY_FN
-            | KeyCode::KEY_UP
-            | KeyCode::KEY_END
-            | KeyCode::KEY_ESC
-            | KeyCode::KEY_F10
-            | KeyCode::KEY_F11
-            | KeyCode::KEY_F12
-            | KeyCode::KEY_F13
-            | KeyCode::KEY_F14
-            | KeyCode::KEY_F15
-            | KeyCode::KEY_F16
-            | KeyCode::KEY_F17
-            | KeyCode::KEY_F18
-            | KeyCode::KEY_F19
-            | KeyCode::KEY_F20
-            | KeyCode::KEY_F21
-            | KeyCode::KEY_F22
-            | KeyCode::KEY_F23
-            | KeyCode::KEY_F24
-            | KeyCode::KEY_BACK
-            | KeyCode::KEY_DOWN
-            | KeyCode::KEY_HOME
-            | KeyCode::KEY_LEFT
-            | KeyCode::KEY_MODE
-            | KeyCode::KEY_MOVE
-            | KeyCode::KEY_ENTER
-            | KeyCode::KEY_RIGHT
-            | KeyCode::KEY_DELETE
-            | KeyCode::KEY_DOLLAR
-            | KeyCode::KEY_INSERT
-            | KeyCode::KEY_PAGEUP
-            | KeyCode::KEY_LEFTALT
-            | KeyCode::KEY_LEFT_UP
-            | KeyCode::KEY_CAPSLOCK
-            | KeyCode::KEY_LEFTCTRL
-            | KeyCode::KEY_LEFTMETA
-            | KeyCode::KEY_PAGEDOWN
-            | KeyCode::KEY_RIGHTALT
-            | KeyCode::KEY_RIGHT_UP
-            | KeyCode::KEY_BACKSPACE
-            | KeyCode::KEY_LEFT_DOWN
-            | KeyCode::KEY_LEFTSHIFT
-            | KeyCode::KEY_RIGHTCTRL
-            | KeyCode::KEY_RIGHTMETA
-            | KeyCode::KEY_RIGHT_DOWN
-            | KeyCode::KEY_RIGHTSHIFT
-            | KeyCode::UNKNOWN => true,
-            _ => false,
-        }
+                | KeyCode::KEY_F2
+                | KeyCode::KEY_F3
+                | KeyCode::KEY_F4
+                | KeyCode::KEY_F5
+                | KeyCode::KEY_F6
+                | KeyCode::KEY_F7
+                | KeyCode::KEY_F8
+                | KeyCode::KEY_F9
+                | KeyCode::KEY_FN
+                | KeyCode::KEY_UP
+                | KeyCode::KEY_END
+                | KeyCode::KEY_ESC
+                | KeyCode::KEY_F10
+                | KeyCode::KEY_F11
+                | KeyCode::KEY_F12
+                | KeyCode::KEY_F13
+                | KeyCode::KEY_F14
+                | KeyCode::KEY_F15
+                | KeyCode::KEY_F16
+                | KeyCode::KEY_F17
+                | KeyCode::KEY_F18
+                | KeyCode::KEY_F19
+                | KeyCode::KEY_F20
+                | KeyCode::KEY_F21
+                | KeyCode::KEY_F22
+                | KeyCode::KEY_F23
+                | KeyCode::KEY_F24
+                | KeyCode::KEY_BACK
+                | KeyCode::KEY_DOWN
+                | KeyCode::KEY_HOME
+                | KeyCode::KEY_LEFT
+                | KeyCode::KEY_MODE
+                | KeyCode::KEY_MOVE
+                | KeyCode::KEY_ENTER
+                | KeyCode::KEY_RIGHT
+                | KeyCode::KEY_DELETE
+                | KeyCode::KEY_DOLLAR
+                | KeyCode::KEY_INSERT
+                | KeyCode::KEY_PAGEUP
+                | KeyCode::KEY_LEFTALT
+                | KeyCode::KEY_LEFT_UP
+                | KeyCode::KEY_CAPSLOCK
+                | KeyCode::KEY_LEFTCTRL
+                | KeyCode::KEY_LEFTMETA
+                | KeyCode::KEY_PAGEDOWN
+                | KeyCode::KEY_RIGHTALT
+                | KeyCode::KEY_RIGHT_UP
+                | KeyCode::KEY_BACKSPACE
+                | KeyCode::KEY_LEFT_DOWN
+                | KeyCode::KEY_LEFTSHIFT
+                | KeyCode::KEY_RIGHTCTRL
+                | KeyCode::KEY_RIGHTMETA
+                | KeyCode::KEY_RIGHT_DOWN
+                | KeyCode::KEY_RIGHTSHIFT
+                | KeyCode::KEY_PAUSE
+                | KeyCode::UNKNOWN
+        )
     }
 }
 
@@ -398,6 +419,7 @@ impl FromStr for KeyCode {
             "<rightbrace>" => KeyCode::KEY_RIGHTBRACE,
             "<right_down>" => KeyCode::KEY_RIGHT_DOWN,
             "<rightshift>" => KeyCode::KEY_RIGHTSHIFT,
+            "<pause>" => KeyCode::KEY_PAUSE,
             "<???>" => KeyCode::UNKNOWN,
             code => bail!("failed to parse keycode: {}", code),
         })
@@ -0,0 +1,281 @@
+use anyhow::*;
+use evdev_rs::enums::{int_to_ev_key, EV_KEY};
+use std::{collections::HashMap, fmt, sync::Arc};
+use xkbcommon::xkb;
+
+use super::{
+    key_code::KeyCode,
+    output_char::{OutputChar, UnicodeKeys},
+};
+
+/// xkb keycodes are evdev keycodes shifted by 8, for historical reasons.
+const EVDEV_OFFSET: u32 = 8;
+
+/// The rules, model, layout, variant and options an xkb keymap is compiled from.
+/// Empty fields fall back to the `XKB_DEFAULT_*` environment variables, and then to the system defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XkbNames {
+    pub rules: String,
+    pub model: String,
+    pub layout: String,
+    pub variant: String,
+    pub options: Option<String>,
+}
+
+impl fmt::Display for XkbNames {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rules '{}', model '{}', layout '{}', variant '{}', options '{}'",
+            self.rules,
+            self.model,
+            self.layout,
+            self.variant,
+            self.options.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// The keys, together with their modifiers, that type each character on a keyboard layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    chars: HashMap<char, OutputChar>,
+    /// keys for typing the characters the layout doesn't contain
+    unicode_keys: Arc<UnicodeKeys>,
+}
+
+impl Layout {
+    /// Compile the given xkb keymap, and look up which key and modifiers type each of its characters.
+    pub fn from_xkb(names: &XkbNames) -> Result<Layout> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            &names.rules,
+            &names.model,
+            &names.layout,
+            &names.variant,
+            names.options.clone(),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .with_context(|| format!("Failed to compile xkb keymap with {}", names))?;
+
+        // ordered by preference, so characters that can be typed without AltGr never use it
+        let modifier_sets: &[&[KeyCode]] = &[
+            &[],
+            &[KeyCode::KEY_LEFTSHIFT],
+            &[KeyCode::KEY_RIGHTALT],
+            &[KeyCode::KEY_RIGHTALT, KeyCode::KEY_LEFTSHIFT],
+        ];
+
+        let mut chars = HashMap::new();
+        for modifiers in modifier_sets {
+            let mut state = xkb::State::new(&keymap);
+            for modifier in modifiers.iter() {
+                state.update_key(xkb_keycode(*modifier), xkb::KeyDirection::Down);
+            }
+            for code in keymap.min_keycode().raw()..=keymap.max_keycode().raw() {
+                let key = match code
+                    .checked_sub(EVDEV_OFFSET)
+                    .and_then(int_to_ev_key)
+                    .map(KeyCode::from)
+                {
+                    Some(key) if key != KeyCode::UNKNOWN && !key.is_modifier() => key,
+                    _ => continue,
+                };
+                match std::char::from_u32(state.key_get_utf32(code.into())) {
+                    Some(c) if !c.is_control() => {
+                        chars
+                            .entry(c)
+                            .or_insert_with(|| output_char(modifiers, key, c));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // keys the layout doesn't have are typed as on a US layout, which is what GTK falls back to as well
+        let unicode_keys = UnicodeKeys::new(|c| match chars.get(&c) {
+            Some(output) => Ok(output.clone()),
+            None => OutputChar::from_char(c),
+        })?;
+        Ok(Layout {
+            chars,
+            unicode_keys: Arc::new(unicode_keys),
+        })
+    }
+
+    /// Turn a character into the key that types it on this layout.
+    /// Characters that the layout doesn't contain become `OutputChar::Unicode`.
+    pub fn output_char(&self, c: char) -> Result<OutputChar> {
+        match c {
+            '\n' => Ok(OutputChar::from(KeyCode::KEY_ENTER)),
+            '\t' => Ok(OutputChar::from(KeyCode::KEY_TAB)),
+            c => match self.chars.get(&c) {
+                Some(output) => Ok(output.clone()),
+                None if c.is_control() => bail!("Error parsing output character {:?}", c),
+                None => Ok(OutputChar::Unicode(c, Some(self.unicode_keys.clone()))),
+            },
+        }
+    }
+
+    /// The key that types the given character on this layout, regardless of the modifiers it needs.
+    pub fn key(&self, c: char) -> Option<KeyCode> {
+        match self.chars.get(&c)? {
+            OutputChar::Key { key, .. } | OutputChar::Combo { key, .. } => Some(*key),
+            OutputChar::Unicode(..) | OutputChar::CapitalizeNext | OutputChar::Attach => None,
+        }
+    }
+}
+
+fn output_char(modifiers: &[KeyCode], key: KeyCode, c: char) -> OutputChar {
+    match modifiers {
+        [] => OutputChar::Key {
+            key,
+            is_upper: false,
+            text: Some(c),
+        },
+        [modifier] if modifier.is_shift() => OutputChar::Key {
+            key,
+            is_upper: true,
+            text: Some(c),
+        },
+        _ => OutputChar::Combo {
+            modifiers: modifiers.to_vec(),
+            key,
+            text: Some(c),
+        },
+    }
+}
+
+fn xkb_keycode(key: KeyCode) -> xkb::Keycode {
+    xkb::Keycode::new(EV_KEY::from(key) as u32 + EVDEV_OFFSET)
+}
+
+/// Compile a real layout for a test, or `None` if xkeyboard-config, which has the keymaps, isn't installed,
+/// in which case the test should be skipped.
+#[cfg(test)]
+pub(crate) fn test_layout(layout: &str, variant: &str) -> Option<Layout> {
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    if !context
+        .include_paths()
+        .any(|path| path.join("rules").join("evdev").exists())
+    {
+        eprintln!(
+            "skipping a test of the {} layout, as xkeyboard-config isn't installed",
+            layout
+        );
+        return None;
+    }
+    let names = XkbNames {
+        layout: layout.to_owned(),
+        variant: variant.to_owned(),
+        ..XkbNames::default()
+    };
+    Some(Layout::from_xkb(&names).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_german_layout() {
+        use KeyCode::*;
+        let layout = match test_layout("de", "") {
+            Some(layout) => layout,
+            None => return,
+        };
+        assert_eq!(
+            OutputChar::Key {
+                key: KEY_Y,
+                is_upper: false,
+                text: Some('z')
+            },
+            layout.output_char('z').unwrap()
+        );
+        assert_eq!(
+            OutputChar::Key {
+                key: KEY_Z,
+                is_upper: true,
+                text: Some('Y')
+            },
+            layout.output_char('Y').unwrap()
+        );
+        let umlaut = layout.output_char('ö').unwrap();
+        assert_eq!(
+            OutputChar::Key {
+                key: KEY_SEMICOLON,
+                is_upper: false,
+                text: Some('ö')
+            },
+            umlaut
+        );
+        assert_eq!(layout.output_char('Ö').ok(), umlaut.capitalized());
+        assert_eq!("ö", umlaut.to_string());
+        assert_eq!(
+            OutputChar::Combo {
+                modifiers: vec![KEY_RIGHTALT],
+                key: KEY_Q,
+                text: Some('@')
+            },
+            layout.output_char('@').unwrap()
+        );
+        assert_eq!("@", layout.output_char('@').unwrap().to_string());
+        assert_eq!(
+            OutputChar::Unicode('日', Some(layout.unicode_keys.clone())),
+            layout.output_char('日').unwrap()
+        );
+        assert_eq!(
+            OutputChar::from(KEY_ENTER),
+            layout.output_char('\n').unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dvorak_unicode_keys() {
+        use KeyCode::*;
+        let layout = match test_layout("us", "dvorak") {
+            Some(layout) => layout,
+            None => return,
+        };
+        let euro = match layout.output_char('€').unwrap() {
+            OutputChar::Unicode('€', Some(keys)) => keys.keys('€'),
+            other => panic!("{:?} isn't typed by its code point", other),
+        };
+        let key = |key, text| OutputChar::Key {
+            key,
+            is_upper: false,
+            text: Some(text),
+        };
+        assert_eq!(
+            vec![
+                OutputChar::Combo {
+                    modifiers: vec![KEY_LEFTCTRL, KEY_LEFTSHIFT],
+                    key: KEY_F,
+                    text: None
+                },
+                key(KEY_2, '2'),
+                key(KEY_0, '0'),
+                key(KEY_A, 'a'),
+                key(KEY_I, 'c'),
+                OutputChar::from(KEY_SPACE),
+            ],
+            euro
+        );
+    }
+
+    #[test]
+    fn test_colemak_chord_keys() {
+        use crate::keyboard::chord::parse_keys;
+        use KeyCode::*;
+        let layout = match test_layout("us", "colemak") {
+            Some(layout) => layout,
+            None => return,
+        };
+        assert_eq!(
+            vec![KEY_A, KEY_S, KEY_D, KEY_F, KEY_BACKSPACE],
+            parse_keys("arst<backspace>", Some(&layout))
+        );
+        assert_eq!(Some(KEY_SEMICOLON), layout.key('O'));
+    }
+}
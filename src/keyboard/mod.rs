@@ -1,19 +1,50 @@
-use self::{key_code::KeyCode, output_char::OutputChar};
+use self::{
+    key_code::KeyCode,
+    output_char::{OutputChar, UnicodeKeys},
+};
 use anyhow::*;
+use std::time::Duration;
 
 pub mod chord;
+pub mod discovery;
 pub mod ev_dev;
 pub mod key_code;
+pub mod layout;
 pub mod output_char;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeyEvent {
     KeyDown(KeyCode),
     KeyUp(KeyCode),
+    /// autorepeat of a key that is being held down
+    Repeat(KeyCode),
+    /// a key that has no `KeyCode`, like a keypad key or a mouse button, given by its evdev code and value.
+    /// It can't be part of a chord, so it is passed on as it is.
+    Opaque {
+        code: u16,
+        value: i32,
+    },
+}
+
+/// A `KeyEvent` read from a backend, together with the time it happened at.
+/// The time is only meaningful relative to the times of other events from the same backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedKeyEvent {
+    pub event: KeyEvent,
+    pub time: Duration,
+    /// id of the device the event came from, unique within the backend.
+    pub device: usize,
 }
 
 pub trait Backend {
     /// read events from the backend, and execute the given lambda on each of these events.
-    fn handle_events<F: FnMut(KeyEvent)>(&self, f: F) -> Result<()>;
+    fn handle_events<F: FnMut(TimedKeyEvent)>(&self, f: F) -> Result<()>;
+
+    /// Whether the backend keeps the original key events from reaching any other applications.
+    /// If so, every event that isn't part of a chord needs to be re-sent via `send_key_event`.
+    fn is_exclusive(&self) -> bool {
+        false
+    }
 
     /// Write a keyevent to the backend
     fn send_key_event(&self, event: KeyEvent) -> Result<()>;
@@ -28,12 +59,34 @@ pub trait Backend {
 
     /// Write a single `OutputChar`
     fn write_char(&self, output: &OutputChar) -> Result<()> {
-        if output.is_upper() {
-            self.send_key_event(KeyEvent::KeyDown(KeyCode::KEY_LEFTSHIFT))?;
-        }
-        self.press_key(output.key)?;
-        if output.is_upper() {
-            self.send_key_event(KeyEvent::KeyUp(KeyCode::KEY_LEFTSHIFT))?;
+        match output {
+            OutputChar::Key { key, is_upper, .. } => {
+                if *is_upper {
+                    self.send_key_event(KeyEvent::KeyDown(KeyCode::KEY_LEFTSHIFT))?;
+                }
+                self.press_key(*key)?;
+                if *is_upper {
+                    self.send_key_event(KeyEvent::KeyUp(KeyCode::KEY_LEFTSHIFT))?;
+                }
+            }
+            OutputChar::Combo { modifiers, key, .. } => {
+                for modifier in modifiers {
+                    self.send_key_event(KeyEvent::KeyDown(*modifier))?;
+                }
+                self.press_key(*key)?;
+                for modifier in modifiers.iter().rev() {
+                    self.send_key_event(KeyEvent::KeyUp(*modifier))?;
+                }
+            }
+            // Ctrl+Shift+U, followed by the hex code point and a space, as understood by GTK, Qt and IBus
+            OutputChar::Unicode(c, keys) => {
+                let keys = match keys {
+                    Some(keys) => keys.keys(*c),
+                    None => UnicodeKeys::new(OutputChar::from_char)?.keys(*c),
+                };
+                self.write_chars(&keys)?;
+            }
+            OutputChar::CapitalizeNext | OutputChar::Attach => {}
         }
         Ok(())
     }
@@ -1,61 +1,434 @@
 use anyhow::*;
 use serde::de::Error;
+use std::sync::Arc;
 
-use crate::keyboard::key_code::KeyCode;
+use crate::keyboard::{key_code::KeyCode, layout::Layout};
 
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub struct OutputChar {
-    pub key: KeyCode,
-    pub is_upper: bool,
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum OutputChar {
+    /// A single key, pressed together with shift if `is_upper` is set.
+    /// `text` is the character it types, if it was given as one. Otherwise, it types what it does on a US layout.
+    Key {
+        key: KeyCode,
+        is_upper: bool,
+        text: Option<char>,
+    },
+    /// A key pressed while holding the given modifiers, written like `<ctrl-shift-t>`,
+    /// or typing `text` on a layout that needs AltGr for it.
+    Combo {
+        modifiers: Vec<KeyCode>,
+        key: KeyCode,
+        text: Option<char>,
+    },
+    /// Any character that can't be typed with a single key, typed by its code point after Ctrl+Shift+U.
+    /// The keys for that are the ones of the layout the output was given in, or of a US layout if there are none.
+    Unicode(char, Option<Arc<UnicodeKeys>>),
+    /// Types nothing, but capitalizes the next letter that is typed by a chord, written as `<cap-next>`.
+    CapitalizeNext,
+    /// Types nothing, but keeps any space from being added between this and the output next to it,
+    /// written as `<attach>`. Like Plover's `{^}`.
+    Attach,
 }
 
+/// The keys that type the `u` of Ctrl+Shift+U, and the hex digits of a code point after it, on some layout.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct UnicodeKeys {
+    /// the `u`, already together with Ctrl and Shift
+    start: OutputChar,
+    /// the digits `0` to `9`, followed by `a` to `f`
+    digits: Vec<OutputChar>,
+}
+
+impl UnicodeKeys {
+    /// Look up the keys with the given function, which turns a character into the key that types it.
+    pub fn new<F: Fn(char) -> Result<OutputChar>>(output_char: F) -> Result<UnicodeKeys> {
+        let start = match output_char('u')? {
+            OutputChar::Key { key, .. } => OutputChar::Combo {
+                modifiers: vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTSHIFT],
+                key,
+                text: None,
+            },
+            OutputChar::Combo { modifiers, key, .. } => OutputChar::Combo {
+                modifiers: vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTSHIFT]
+                    .into_iter()
+                    .chain(modifiers)
+                    .collect(),
+                key,
+                text: None,
+            },
+            other => bail!("{} can't start Unicode input", other),
+        };
+        let digits = "0123456789abcdef"
+            .chars()
+            .map(&output_char)
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(digit) = digits
+            .iter()
+            .find(|digit| matches!(digit, OutputChar::Unicode(..)))
+        {
+            bail!("The hex digit {} has no key", digit);
+        }
+        Ok(UnicodeKeys { start, digits })
+    }
+
+    /// The keys that type the given character, starting with Ctrl+Shift+U and ending with the space that confirms it.
+    pub fn keys(&self, c: char) -> Vec<OutputChar> {
+        let mut keys = vec![self.start.clone()];
+        for digit in format!("{:x}", c as u32).chars() {
+            let digit = digit.to_digit(16).unwrap() as usize;
+            keys.push(self.digits[digit].clone());
+        }
+        keys.push(OutputChar::from(KeyCode::KEY_SPACE));
+        keys
+    }
+}
+
+/// Characters that are typed by a key other than their name on a US layout, and whether they need shift.
+const US_LAYOUT: &[(char, KeyCode, bool)] = &[
+    ('-', KeyCode::KEY_MINUS, false),
+    ('=', KeyCode::KEY_EQUAL, false),
+    ('[', KeyCode::KEY_LEFTBRACE, false),
+    (']', KeyCode::KEY_RIGHTBRACE, false),
+    ('\\', KeyCode::KEY_BACKSLASH, false),
+    (';', KeyCode::KEY_SEMICOLON, false),
+    ('\'', KeyCode::KEY_APOSTROPHE, false),
+    ('`', KeyCode::KEY_GRAVE, false),
+    (',', KeyCode::KEY_COMMA, false),
+    ('.', KeyCode::KEY_DOT, false),
+    ('/', KeyCode::KEY_SLASH, false),
+    ('!', KeyCode::KEY_1, true),
+    ('@', KeyCode::KEY_2, true),
+    ('#', KeyCode::KEY_3, true),
+    ('$', KeyCode::KEY_4, true),
+    ('%', KeyCode::KEY_5, true),
+    ('^', KeyCode::KEY_6, true),
+    ('&', KeyCode::KEY_7, true),
+    ('*', KeyCode::KEY_8, true),
+    ('(', KeyCode::KEY_9, true),
+    (')', KeyCode::KEY_0, true),
+    ('_', KeyCode::KEY_MINUS, true),
+    ('+', KeyCode::KEY_EQUAL, true),
+    ('{', KeyCode::KEY_LEFTBRACE, true),
+    ('}', KeyCode::KEY_RIGHTBRACE, true),
+    ('|', KeyCode::KEY_BACKSLASH, true),
+    (':', KeyCode::KEY_SEMICOLON, true),
+    ('"', KeyCode::KEY_APOSTROPHE, true),
+    ('~', KeyCode::KEY_GRAVE, true),
+    ('<', KeyCode::KEY_COMMA, true),
+    ('>', KeyCode::KEY_DOT, true),
+    ('?', KeyCode::KEY_SLASH, true),
+];
+
 impl<'de> serde::Deserialize<'de> for OutputChar {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         let c = char::deserialize(deserializer)?;
-        OutputChar::from_char(c).map_err(|e| D::Error::custom(e))
+        OutputChar::from_char(c).map_err(D::Error::custom)
+    }
+}
+
+impl std::fmt::Display for OutputChar {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OutputChar::Key { key, is_upper, .. } => match self.as_char() {
+                Some(c) => write!(f, "{}", c),
+                None if *is_upper => write!(f, "{}", key.as_string().to_uppercase()),
+                None => write!(f, "{}", key.as_string()),
+            },
+            OutputChar::Unicode(c, _) => write!(f, "{}", c),
+            OutputChar::CapitalizeNext => write!(f, "<cap-next>"),
+            OutputChar::Attach => write!(f, "<attach>"),
+            OutputChar::Combo { text: Some(c), .. } => write!(f, "{}", c),
+            OutputChar::Combo { modifiers, key, .. } => {
+                write!(f, "<")?;
+                for modifier in modifiers {
+                    write!(f, "{}-", modifier_name(*modifier))?;
+                }
+                write!(
+                    f,
+                    "{}>",
+                    key.as_string().trim_matches(|c| c == '<' || c == '>')
+                )
+            }
+        }
     }
 }
 
 impl From<KeyCode> for OutputChar {
     fn from(key: KeyCode) -> Self {
-        OutputChar {
+        OutputChar::Key {
             key,
             is_upper: false,
+            text: None,
         }
     }
 }
 
 impl OutputChar {
     pub fn is_upper(&self) -> bool {
-        self.is_upper
+        matches!(self, OutputChar::Key { is_upper: true, .. })
+    }
+
+    /// The character this types, if it types one. Special keys like `<enter>` don't.
+    pub fn as_char(&self) -> Option<char> {
+        match self {
+            OutputChar::Key { text: Some(c), .. }
+            | OutputChar::Combo { text: Some(c), .. }
+            | OutputChar::Unicode(c, _) => Some(*c),
+            OutputChar::Key {
+                key,
+                is_upper,
+                text: None,
+            } => {
+                if let Some((c, _, _)) = US_LAYOUT
+                    .iter()
+                    .find(|(_, k, shift)| k == key && shift == is_upper)
+                {
+                    return Some(*c);
+                }
+                let mut chars = key.as_string().chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if *is_upper => Some(c.to_ascii_uppercase()),
+                    (Some(c), None) => Some(c),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The upper case version of this, if it is a lower case letter.
+    /// Letters typed by a single key are assumed to be typed in upper case with shift.
+    pub fn capitalized(&self) -> Option<OutputChar> {
+        let c = self.as_char().filter(|c| c.is_lowercase())?;
+        let mut upper = c.to_uppercase();
+        let upper = match (upper.next(), upper.next()) {
+            (Some(upper), None) => upper,
+            _ => return None,
+        };
+        match self {
+            OutputChar::Key {
+                key,
+                is_upper: false,
+                ..
+            } => Some(OutputChar::Key {
+                key: *key,
+                is_upper: true,
+                text: Some(upper),
+            }),
+            OutputChar::Unicode(_, keys) => Some(OutputChar::Unicode(upper, keys.clone())),
+            _ => None,
+        }
     }
 
+    /// Turn a character into the key that types it on a US layout.
+    /// Characters that don't have a key of their own become `OutputChar::Unicode`.
     pub fn from_char(c: char) -> Result<OutputChar> {
-        let key = c
-            .to_ascii_lowercase()
-            .to_string()
+        match c {
+            '\n' => return Ok(OutputChar::from(KeyCode::KEY_ENTER)),
+            '\t' => return Ok(OutputChar::from(KeyCode::KEY_TAB)),
+            _ => {}
+        }
+        if let Some((_, key, shift)) = US_LAYOUT.iter().find(|(x, _, _)| *x == c) {
+            return Ok(OutputChar::Key {
+                key: *key,
+                is_upper: *shift,
+                text: Some(c),
+            });
+        }
+        match c.to_ascii_lowercase().to_string().parse::<KeyCode>() {
+            Ok(key) => Ok(OutputChar::Key {
+                key,
+                is_upper: !key.is_control() && c.is_uppercase(),
+                text: Some(c),
+            }),
+            Err(_) if c.is_control() => bail!("Error parsing output character {:?}", c),
+            Err(_) => Ok(OutputChar::Unicode(c, None)),
+        }
+    }
+
+    /// Parse a key written in angle brackets, like `<enter>`,
+    /// optionally prefixed by modifiers, like `<ctrl-shift-t>`.
+    pub fn from_name(s: &str) -> Result<OutputChar> {
+        let name = s
+            .strip_prefix('<')
+            .and_then(|s| s.strip_suffix('>'))
+            .with_context(|| format!("Key name {} is not written in angle brackets", s))?;
+
+        // `<` and `>` themselves need to be escaped, as they would otherwise start a key name
+        match name {
+            "lt" => return OutputChar::from_char('<'),
+            "gt" => return OutputChar::from_char('>'),
+            "cap-next" => return Ok(OutputChar::CapitalizeNext),
+            "attach" => return Ok(OutputChar::Attach),
+            _ => {}
+        }
+
+        let mut parts: Vec<&str> = name.split('-').collect();
+        // allow for `<ctrl-->`, as the key itself might be a `-`
+        let key = match parts.pop() {
+            Some("") if name.ends_with("--") => {
+                parts.pop();
+                "-"
+            }
+            Some(key) => key,
+            None => bail!("Empty key name"),
+        };
+        let key: KeyCode = format!("<{}>", key)
             .parse()
-            .context("Error parsing output character")?;
-        Ok(OutputChar {
-            key,
-            is_upper: !key.is_control() && c.is_uppercase(),
-        })
+            .or_else(|_| key.parse())
+            .with_context(|| format!("Unknown key in {}", s))?;
+
+        let modifiers = parts
+            .into_iter()
+            .map(|modifier| {
+                modifier_from_name(modifier)
+                    .with_context(|| format!("Unknown modifier {} in {}", modifier, s))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if modifiers.is_empty() {
+            Ok(OutputChar::from(key))
+        } else {
+            Ok(OutputChar::Combo {
+                modifiers,
+                key,
+                text: None,
+            })
+        }
     }
+}
 
-    //pub fn from_str(s: &str) -> Result<OutputChar> {
-    //let key = s
-    //.parse()
-    //.with_context(|| format!("Error parsing output character in string {}", s))?;
-    //Ok(OutputChar {
-    //key,
-    //is_upper: !key.is_control() && s.chars().next().unwrap().is_uppercase(),
-    //})
-    //}
+fn modifier_from_name(name: &str) -> Option<KeyCode> {
+    Some(match name {
+        "ctrl" => KeyCode::KEY_LEFTCTRL,
+        "shift" => KeyCode::KEY_LEFTSHIFT,
+        "alt" => KeyCode::KEY_LEFTALT,
+        "altgr" => KeyCode::KEY_RIGHTALT,
+        "meta" | "super" => KeyCode::KEY_LEFTMETA,
+        _ => return None,
+    })
 }
 
-pub fn output_chars_from_string(s: &str) -> Result<Vec<OutputChar>> {
-    s.chars().map(|x| OutputChar::from_char(x)).collect()
+fn modifier_name(modifier: KeyCode) -> &'static str {
+    match modifier {
+        KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL => "ctrl",
+        KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT => "shift",
+        KeyCode::KEY_LEFTALT => "alt",
+        KeyCode::KEY_RIGHTALT => "altgr",
+        KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA => "meta",
+        other => other.as_string(),
+    }
+}
+
+/// Parse the output of a mapping. Special keys and key combinations can be written in angle brackets,
+/// like `<enter>` or `<ctrl-backspace>`. Literal angle brackets are written as `<lt>` and `<gt>`.
+/// All other characters are typed with the keys of the given layout, or of a US layout if there is none.
+pub fn output_chars_from_string(s: &str, layout: Option<&Layout>) -> Result<Vec<OutputChar>> {
+    let pattern = regex::Regex::new("<[^<>]+>|.").unwrap();
+    pattern
+        .find_iter(s)
+        .map(|part| match part.as_str() {
+            name if name.starts_with('<') && name.len() > 1 => OutputChar::from_name(name),
+            c => {
+                let c = c.chars().next().unwrap();
+                match layout {
+                    Some(layout) => layout.output_char(c),
+                    None => OutputChar::from_char(c),
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_special_keys() {
+        use KeyCode::*;
+        assert_eq!(
+            vec![
+                OutputChar::Key {
+                    key: KEY_A,
+                    is_upper: false,
+                    text: Some('a')
+                },
+                OutputChar::from(KEY_ENTER),
+                OutputChar::Key {
+                    key: KEY_B,
+                    is_upper: true,
+                    text: Some('B')
+                }
+            ],
+            output_chars_from_string("a<enter>B", None).unwrap()
+        );
+        assert_eq!(
+            vec![OutputChar::Combo {
+                modifiers: vec![KEY_LEFTCTRL, KEY_LEFTSHIFT],
+                key: KEY_T,
+                text: None
+            }],
+            output_chars_from_string("<ctrl-shift-t>", None).unwrap()
+        );
+        assert_eq!(
+            vec![OutputChar::Combo {
+                modifiers: vec![KEY_LEFTCTRL],
+                key: KEY_BACKSPACE,
+                text: None
+            }],
+            output_chars_from_string("<ctrl-backspace>", None).unwrap()
+        );
+        assert_eq!(
+            vec![
+                OutputChar::Key {
+                    key: KEY_1,
+                    is_upper: true,
+                    text: Some('!')
+                },
+                OutputChar::Key {
+                    key: KEY_COMMA,
+                    is_upper: true,
+                    text: Some('<')
+                },
+                OutputChar::Unicode('é', None),
+                OutputChar::Unicode('—', None),
+            ],
+            output_chars_from_string("!<lt>é—", None).unwrap()
+        );
+        assert!(output_chars_from_string("<hyper-a>", None).is_err());
+        assert!(output_chars_from_string("<nonsense>", None).is_err());
+    }
+
+    #[test]
+    fn test_unicode_keys() {
+        let keys = UnicodeKeys::new(OutputChar::from_char).unwrap();
+        assert_eq!(
+            "<ctrl-shift-u>2014 ",
+            keys.keys('—')
+                .iter()
+                .map(ToString::to_string)
+                .collect::<String>()
+        );
+        assert_eq!(OutputChar::from_char('b').unwrap(), keys.digits[11]);
+    }
+
+    #[test]
+    fn test_display() {
+        for s in &[
+            "hello",
+            "a<enter>",
+            ".<cap-next>",
+            "<ctrl-shift-t>",
+            "<ctrl-backspace>",
+            "Grüße, Straße!",
+        ] {
+            let output = output_chars_from_string(s, None).unwrap();
+            assert_eq!(*s, output.iter().map(|c| c.to_string()).collect::<String>());
+        }
+    }
 }
@@ -1,36 +1,336 @@
-use app::App;
-use keyboard::ev_dev::EvDevBackend;
-use mappings::Mappings;
+use app::{App, AppConfig};
+use diagnostics::{check_mappings, Diagnostic, Fingers};
+use dictionaries::{
+    charachorder::{export_csv, import_csv},
+    plover::{convert_dictionary, StenoMap},
+    Conversion,
+};
+use generate::{generate, GenerateConfig, Rolls};
+use keyboard::{
+    chord::{parse_keys, Chord},
+    discovery::{list_keyboards, DeviceInfo, DeviceSelector},
+    ev_dev::EvDevBackend,
+    key_code::KeyCode,
+    layout::Layout,
+};
+use mappings::{write_entries, Format, Mappings, MappingsSource};
+use opts::{Command, Opt};
+use std::{path::Path, time::Duration};
+use structopt::StructOpt;
 
 use anyhow::*;
+use itertools::Itertools;
 
 pub mod app;
+pub mod diagnostics;
+pub mod dictionaries;
+pub mod generate;
 pub mod history;
 pub mod keyboard;
 pub mod mappings;
+pub mod opts;
+pub mod reload;
 
 fn main() -> Result<()> {
-    let mappings_file = std::fs::File::open("/home/leon/.config/chordthingy/mappings.json")?;
-    let mappings = Mappings::from_reader(mappings_file)?;
-    dbg!(&mappings);
+    let opt = Opt::from_args();
 
-    let backend = init_evdev_backend()?;
+    match opt.command {
+        Some(Command::ListDevices) => {
+            for device in list_keyboards()? {
+                println!("{}", device);
+            }
+        }
+        Some(Command::List) => {
+            let mappings = load_mappings(&opt.mappings_path()?, &opt)?;
+            print_mappings(&mappings);
+        }
+        Some(Command::Check { ref file }) => {
+            let path = match file {
+                Some(file) => file.clone(),
+                None => opt.mappings_path()?,
+            };
+            let (mappings, diagnostics) = read_mappings(&path, &opt)?;
+            if opt.verbose > 0 {
+                print_mappings(&mappings);
+            }
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+            if !diagnostics.is_empty() {
+                bail!("{} problems found", diagnostics.len());
+            }
+            println!("{}: {} mappings, no errors", path.display(), mappings.len());
+        }
+        Some(Command::ImportPlover {
+            ref dictionary,
+            ref steno_map,
+            ref output,
+        }) => {
+            let steno_map = match steno_map {
+                Some(path) => StenoMap::from_reader(
+                    std::fs::File::open(path)
+                        .with_context(|| format!("Failed to open steno map {}", path.display()))?,
+                )?,
+                None => StenoMap::default(),
+            };
+            let text = std::fs::read_to_string(dictionary)
+                .with_context(|| format!("Failed to read {}", dictionary.display()))?;
+            let conversion = convert_dictionary(&text, &steno_map)
+                .with_context(|| format!("Invalid Plover dictionary {}", dictionary.display()))?
+                .in_file(dictionary);
+            write_conversion(&conversion, output.as_deref())?;
+        }
+        Some(Command::ImportCsv {
+            ref library,
+            ref output,
+        }) => {
+            let text = std::fs::read_to_string(library)
+                .with_context(|| format!("Failed to read {}", library.display()))?;
+            let conversion = import_csv(&text)
+                .with_context(|| format!("Invalid chord library {}", library.display()))?
+                .in_file(library);
+            write_conversion(&conversion, output.as_deref())?;
+        }
+        Some(Command::ExportCsv { ref output }) => {
+            let layout = read_layout(&opt)?;
+            let chord_layout = chord_layout(layout.as_ref(), &opt)?;
+            let source = MappingsSource::load(&opt.mappings_path()?, chord_layout)?;
+            let (csv, skipped) = export_csv(&source.entries, chord_layout)?;
+            for skipped in &skipped {
+                eprintln!("skipped {}", skipped);
+            }
+            match output {
+                Some(path) => std::fs::write(path, csv)
+                    .with_context(|| format!("Failed to write {}", path.display()))?,
+                None => print!("{}", csv),
+            }
+            eprintln!("Skipped {} entries the chorder can't type", skipped.len());
+        }
+        Some(Command::Generate {
+            ref words,
+            ref corpus,
+            max_keys,
+            max_rolls,
+            ref output,
+        }) => {
+            let layout = read_layout(&opt)?;
+            let chord_layout = chord_layout(layout.as_ref(), &opt)?;
+            let fingers = read_fingers(&opt, chord_layout)?;
+            let mappings_path = opt.mappings_path()?;
+            let existing = if opt.mappings.is_some() || mappings_path.exists() {
+                MappingsSource::load(&mappings_path, chord_layout)?.entries
+            } else {
+                Vec::new()
+            };
+            let rolls = match corpus {
+                Some(corpus) => {
+                    let text = std::fs::read_to_string(corpus)
+                        .with_context(|| format!("Failed to read {}", corpus.display()))?;
+                    Some(Rolls::from_text(&text, max_keys, chord_layout))
+                }
+                None => None,
+            };
+            let text = std::fs::read_to_string(words)
+                .with_context(|| format!("Failed to read {}", words.display()))?;
+            let config = GenerateConfig {
+                max_keys,
+                max_rolls,
+            };
+            let conversion = generate(
+                &text,
+                &existing,
+                rolls.as_ref(),
+                chord_layout,
+                fingers.as_ref(),
+                config,
+            )
+            .with_context(|| format!("Invalid word list {}", words.display()))?
+            .in_file(words);
+            write_conversion(&conversion, output.as_deref())?;
+        }
+        None => run(opt)?,
+    }
+    Ok(())
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let mappings_path = opt.mappings_path()?;
+    let mappings = load_mappings(&mappings_path, &opt)?;
+    if opt.verbose > 1 {
+        print_mappings(&mappings);
+    }
+
+    // without any explicit selectors, use the first keyboard, and reattach it by its name
+    let selectors = if opt.devices.is_empty() {
+        let keyboard = list_keyboards()?
+            .into_iter()
+            .next()
+            .context("No keyboard found")?;
+        vec![DeviceSelector::Name(keyboard.name)]
+    } else {
+        opt.devices.clone()
+    };
+    let devices = selectors
+        .iter()
+        .map(|selector| selector.find())
+        .collect::<Result<Vec<_>>>()?;
+    let mut backend = init_evdev_backend(&devices, !opt.dry_run && !opt.no_grab)?;
+    backend.enable_hotplug(selectors)?;
+
+    let undo = parse_chord_option(opt.undo.as_deref(), "undo", &opt)?;
+    let next_candidate = parse_chord_option(opt.next_candidate.as_deref(), "next candidate", &opt)?;
+
+    let default_config = AppConfig::default();
+    let config = AppConfig {
+        press_window: opt
+            .press_window
+            .map_or(default_config.press_window, Duration::from_millis),
+        min_hold: opt
+            .min_hold
+            .map_or(default_config.min_hold, Duration::from_millis),
+        per_device: opt.per_device,
+        verbose: opt.verbose > 0,
+        dry_run: opt.dry_run,
+        suspend_keys: opt.suspend_keys.clone(),
+        undo,
+        next_candidate,
+        sentence_case: !opt.no_sentence_case,
+    };
+    let mut app = App::new(backend, mappings, config)?;
 
-    let mut app = App::new(backend, mappings)?;
+    let reload_opt = opt.clone();
+    reload::watch_mappings(
+        mappings_path,
+        app.mappings(),
+        opt.reload_on_sighup,
+        move |path| load_mappings(path, &reload_opt),
+    )?;
     app.run()?;
     Ok(())
 }
 
-fn init_evdev_backend() -> Result<EvDevBackend> {
-    let device_path = std::env::args()
-        .nth(1)
-        .unwrap_or("/dev/input/event24".to_owned());
+/// Parse a chord given on the command line, like the undo chord.
+fn parse_chord_option(keys: Option<&str>, name: &str, opt: &Opt) -> Result<Option<Chord>> {
+    let keys = match keys {
+        Some(keys) => keys,
+        None => return Ok(None),
+    };
+    let layout = read_layout(opt)?;
+    let parsed = parse_keys(keys, chord_layout(layout.as_ref(), opt)?);
+    if parsed.contains(&KeyCode::UNKNOWN) {
+        bail!("Unknown key in the {} chord {}", name, keys);
+    }
+    Ok(Some(Chord::from_key_codes(parsed)))
+}
+
+/// Print every mapping with the place it is defined at, in the order of the files.
+fn print_mappings(mappings: &Mappings) {
+    let sorted = mappings.iter().sorted_by_key(|mapping| {
+        (
+            mapping.location.file.clone(),
+            mapping.location.line,
+            mapping.location.column,
+        )
+    });
+    for mapping in sorted {
+        println!(
+            "{}: {} -> {}",
+            mapping.location,
+            mapping.chord,
+            mapping
+                .outputs
+                .iter()
+                .map(|output| output.iter().join(""))
+                .join(" | ")
+        );
+    }
+}
+
+/// Read the mappings, printing any problems with them as warnings.
+fn load_mappings(path: &Path, opt: &Opt) -> Result<Mappings> {
+    let (mappings, diagnostics) = read_mappings(path, opt)?;
+    for diagnostic in diagnostics {
+        eprintln!("warning: {}", diagnostic);
+    }
+    Ok(mappings)
+}
+
+fn read_mappings(path: &Path, opt: &Opt) -> Result<(Mappings, Vec<Diagnostic>)> {
+    let layout = read_layout(opt)?;
+    let chord_layout = chord_layout(layout.as_ref(), opt)?;
+    let fingers = read_fingers(opt, chord_layout)?;
+
+    let source = MappingsSource::load(path, chord_layout)?;
+    let mappings = Mappings::from_source(&source, layout.as_ref(), chord_layout)?;
+    let diagnostics = check_mappings(&source.entries, chord_layout, fingers.as_ref());
+    Ok((mappings, diagnostics))
+}
+
+fn read_layout(opt: &Opt) -> Result<Option<Layout>> {
+    opt.xkb_names()
+        .map(|names| Layout::from_xkb(&names))
+        .transpose()
+}
 
-    let device_file = std::fs::File::open(device_path)?;
+/// The layout the chords are written in, if `--layout-chords` is given.
+fn chord_layout<'a>(layout: Option<&'a Layout>, opt: &Opt) -> Result<Option<&'a Layout>> {
+    match layout {
+        Some(layout) if opt.layout_chords => Ok(Some(layout)),
+        None if opt.layout_chords => {
+            bail!(
+                "--layout-chords needs an xkb layout, given by --xkb-layout or XKB_DEFAULT_LAYOUT"
+            )
+        }
+        _ => Ok(None),
+    }
+}
+
+fn read_fingers(opt: &Opt, chord_layout: Option<&Layout>) -> Result<Option<Fingers>> {
+    let fingers_path = match &opt.fingers {
+        Some(fingers_path) => fingers_path,
+        None => return Ok(None),
+    };
+    let file = std::fs::File::open(fingers_path)
+        .with_context(|| format!("Failed to open fingers file {}", fingers_path.display()))?;
+    let fingers = Fingers::from_reader(file, chord_layout)
+        .with_context(|| format!("Invalid fingers file {}", fingers_path.display()))?;
+    Ok(Some(fingers))
+}
+
+/// Write converted mappings to `output`, in the format given by its extension, or as JSON to stdout.
+fn write_conversion(conversion: &Conversion, output: Option<&Path>) -> Result<()> {
+    for skipped in &conversion.skipped {
+        eprintln!("skipped {}", skipped);
+    }
+    let format = match output {
+        Some(path) => Format::from_path(path)?,
+        None => Format::Json,
+    };
+    let written = write_entries(&conversion.entries, format)?;
+    match output {
+        Some(path) => std::fs::write(path, written)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => println!("{}", written),
+    }
+    eprintln!(
+        "Wrote {} mappings, skipped {}",
+        conversion.entries.len(),
+        conversion.skipped.len()
+    );
+    Ok(())
+}
 
-    let mut device = evdev_rs::Device::new().context("Error getting device")?;
-    device.set_fd(device_file)?;
-    let input_device = evdev_rs::UInputDevice::create_from_device(&device)?;
+fn init_evdev_backend(devices: &[DeviceInfo], exclusive: bool) -> Result<EvDevBackend> {
+    let backend = EvDevBackend::new(exclusive)?;
 
-    Ok(EvDevBackend::new(input_device, device))
+    // give the user a moment to release the key that started us,
+    // so its release doesn't get swallowed by the grab.
+    if exclusive {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
+    for device in devices {
+        println!("Using {}", device);
+        backend.add_device(&device.path)?;
+    }
+    Ok(backend)
 }
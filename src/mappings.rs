@@ -1,29 +1,1097 @@
 use anyhow::*;
-use std::collections::HashMap;
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    ser::SerializeMap,
+    Deserialize, Serialize,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use crate::keyboard::{
-    chord::Chord,
+    chord::{parse_keys, Chord},
+    layout::Layout,
     output_char::{output_chars_from_string, OutputChar},
 };
 
+/// Version of the mappings format that is written as `{"version": 2, "mappings": {...}}`.
+/// A plain object of chords and outputs is version 1.
+pub const MAPPINGS_VERSION: u32 = 2;
+
+/// The formats a mappings file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Detect the format of a file by its extension.
+    pub fn from_path(path: &Path) -> Result<Format> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(Format::Json),
+            Some("toml") => Ok(Format::Toml),
+            Some("yaml") | Some("yml") => Ok(Format::Yaml),
+            _ => bail!(
+                "Unknown mappings format of {}, expected a .json, .toml or .yaml file",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Spacing added around the output of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Space {
+    /// exactly as written
+    #[default]
+    None,
+    /// followed by a space
+    After,
+    /// preceded by a space if it follows the output of another chord that ended in something other than a space,
+    /// so words typed by chords are separated, but sentences don't end in a space
+    Auto,
+}
+
+/// Case the output of a mapping is typed in. Key names like `<enter>` are never changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Case {
+    /// exactly as written
+    #[default]
+    Keep,
+    Lower,
+    Upper,
+    /// with the first letter in upper case
+    Capitalize,
+}
+
+impl Case {
+    pub fn apply(self, output: &str) -> String {
+        let key_name = regex::Regex::new("<[^<>]+>").unwrap();
+        let mut result = String::with_capacity(output.len());
+        let mut text_start = 0;
+        let mut capitalized = false;
+        let names = key_name
+            .find_iter(output)
+            .map(|name| (name.start(), name.end()))
+            .chain(Some((output.len(), output.len())));
+        for (start, end) in names {
+            let text = &output[text_start..start];
+            match self {
+                Case::Keep => result.push_str(text),
+                Case::Lower => result.push_str(&text.to_lowercase()),
+                Case::Upper => result.push_str(&text.to_uppercase()),
+                Case::Capitalize => {
+                    for c in text.chars() {
+                        if capitalized {
+                            result.push(c);
+                        } else {
+                            result.extend(c.to_uppercase());
+                            capitalized = c.is_alphabetic();
+                        }
+                    }
+                }
+            }
+            result.push_str(&output[start..end]);
+            text_start = end;
+        }
+        result
+    }
+}
+
+/// The output of an entry in the mappings file, or a list of candidate outputs,
+/// which repeating the chord cycles through.
+#[derive(Debug)]
+enum Outputs {
+    One(String),
+    Candidates(Vec<String>),
+}
+
+impl<'de> Deserialize<'de> for Outputs {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        match deserializer.deserialize_any(ValueVisitor { options: false })? {
+            MappingValue::Output(outputs) => Ok(outputs),
+            MappingValue::Entry(_) => unreachable!("objects are only accepted with options"),
+        }
+    }
+}
+
+impl Outputs {
+    /// The first output, and the ones that can replace it.
+    fn split(self) -> Result<(String, Vec<String>)> {
+        match self {
+            Outputs::One(output) => Ok((output, Vec::new())),
+            Outputs::Candidates(mut outputs) => {
+                if outputs.is_empty() {
+                    bail!("Empty list of outputs");
+                }
+                let first = outputs.remove(0);
+                Ok((first, outputs))
+            }
+        }
+    }
+}
+
+/// The value of an entry in the mappings file.
+/// Either just the output, or an object that also contains options for the mapping.
+#[derive(Debug)]
+enum MappingValue {
+    Output(Outputs),
+    Entry(EntryOptions),
+}
+
+/// An entry of the mappings file written as an object.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EntryOptions {
+    output: Outputs,
+    #[serde(default)]
+    ordered: bool,
+    #[serde(default)]
+    space: Option<Space>,
+    #[serde(default)]
+    case: Case,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for MappingValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ValueVisitor { options: true })
+    }
+}
+
+/// Tells the shapes of `MappingValue` and `Outputs` apart by their type, instead of trying each of them,
+/// so a mistake inside of an entry is reported where it is, rather than as not matching any of them.
+struct ValueVisitor {
+    /// whether an object with options is allowed, which is only the case for a whole entry
+    options: bool,
+}
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = MappingValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.options {
+            write!(
+                f,
+                "an output, a list of outputs, or an object with an output and its options"
+            )
+        } else {
+            write!(f, "an output or a list of outputs")
+        }
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(MappingValue::Output(Outputs::One(v.to_owned())))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        let outputs = Vec::deserialize(SeqAccessDeserializer::new(seq))?;
+        Ok(MappingValue::Output(Outputs::Candidates(outputs)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        if !self.options {
+            return Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::Map,
+                &self,
+            ));
+        }
+        let options = EntryOptions::deserialize(MapAccessDeserializer::new(map))?;
+        Ok(MappingValue::Entry(options))
+    }
+}
+
+/// The entries of a mappings file in the order they are written in, including duplicate keys.
+#[derive(Default)]
+struct OrderedEntries(Vec<(String, MappingValue)>);
+
+impl<'de> Deserialize<'de> for OrderedEntries {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct EntriesVisitor;
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = OrderedEntries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an object of chords and their outputs")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(OrderedEntries(entries))
+            }
+        }
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+/// A versioned mappings file. Files without a version are just an object of chords and outputs.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VersionedFile {
+    /// already checked by reading the `FileVersion` first
+    #[allow(dead_code)]
+    version: u32,
+    /// other mappings files, relative to this one, which this one is layered on top of
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// chords of the included files that should not be used
+    #[serde(default)]
+    remove: Vec<String>,
+    /// spacing of the entries that don't set their own
+    #[serde(default)]
+    space: Space,
+    #[serde(default)]
+    mappings: OrderedEntries,
+}
+
+/// Just the version of a mappings file, which decides how the rest of it is read.
+#[derive(Deserialize)]
+struct FileVersion {
+    #[serde(default)]
+    version: Option<serde_json::Value>,
+}
+
+/// Position of an entry in its mappings file, starting at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// the file the entry is in, if it was read from one
+    pub file: Option<Arc<Path>>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl Location {
+    fn from_offset(text: &str, offset: usize) -> Location {
+        let before = &text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Location {
+            file: None,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+/// A single entry of a mappings file, as it is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingEntry {
+    /// the keys of the chord, as written in the file
+    pub chord: String,
+    pub output: String,
+    /// outputs that replace `output` one after the other when the chord is repeated
+    pub alternatives: Vec<String>,
+    /// whether the keys need to be pressed in the order they are written in
+    pub ordered: bool,
+    pub space: Space,
+    pub case: Case,
+    /// only for the people reading the file
+    pub comment: Option<String>,
+    pub tags: Vec<String>,
+    pub location: Location,
+}
+
+impl MappingEntry {
+    fn new(
+        chord: String,
+        value: MappingValue,
+        default_space: Space,
+        location: Location,
+    ) -> Result<Self> {
+        Ok(match value {
+            MappingValue::Output(outputs) => {
+                let (output, alternatives) =
+                    outputs.split().with_context(|| location.to_string())?;
+                MappingEntry {
+                    chord,
+                    output,
+                    alternatives,
+                    ordered: false,
+                    space: default_space,
+                    case: Case::default(),
+                    comment: None,
+                    tags: Vec::new(),
+                    location,
+                }
+            }
+            MappingValue::Entry(EntryOptions {
+                output,
+                ordered,
+                space,
+                case,
+                comment,
+                tags,
+            }) => {
+                let (output, alternatives) =
+                    output.split().with_context(|| location.to_string())?;
+                MappingEntry {
+                    chord,
+                    output,
+                    alternatives,
+                    ordered,
+                    space: space.unwrap_or(default_space),
+                    case,
+                    comment,
+                    tags,
+                    location,
+                }
+            }
+        })
+    }
+
+    /// The chord of this entry, with its keys read through the given layout.
+    pub fn parse_chord(&self, layout: Option<&Layout>) -> Chord {
+        let keys = parse_keys(&self.chord, layout);
+        if self.ordered {
+            Chord::ordered_from_key_codes(keys)
+        } else {
+            Chord::from_key_codes(keys)
+        }
+    }
+
+    /// The output of this entry with its case and trailing space applied, typed with the keys of the given layout.
+    /// Leading spaces of `Space::Auto` depend on what was typed before, so they are added while typing.
+    pub fn parse_output(&self, layout: Option<&Layout>) -> Result<Vec<OutputChar>> {
+        self.parse_text(&self.output, layout)
+    }
+
+    /// The output and its alternatives, in the order repeating the chord types them in.
+    pub fn parse_outputs(&self, layout: Option<&Layout>) -> Result<Vec<Vec<OutputChar>>> {
+        Some(&self.output)
+            .into_iter()
+            .chain(&self.alternatives)
+            .map(|output| self.parse_text(output, layout))
+            .collect()
+    }
+
+    fn parse_text(&self, output: &str, layout: Option<&Layout>) -> Result<Vec<OutputChar>> {
+        let mut output = self.case.apply(output);
+        // whatever comes next is attached to the output, so there's nothing to separate
+        if self.space == Space::After && !output.ends_with("<attach>") {
+            output.push(' ');
+        }
+        output_chars_from_string(&output, layout)
+    }
+}
+
+/// The contents of a single mappings file.
+struct ParsedFile {
+    include: Vec<PathBuf>,
+    remove: Vec<String>,
+    entries: Vec<MappingEntry>,
+}
+
+/// Parse the entries of a mappings file, in the order they are written in, without looking at its includes.
+pub fn parse_entries(text: &str, format: Format) -> Result<Vec<MappingEntry>> {
+    Ok(parse_file(text, format)?.entries)
+}
+
+/// Deserialize the text of a mappings file in the given format.
+fn deserialize<T: serde::de::DeserializeOwned>(text: &str, format: Format) -> Result<T> {
+    Ok(match format {
+        Format::Json => serde_json::from_str(text).context("Failed to parse JSON mappings")?,
+        Format::Toml => toml::from_str(text).context("Failed to parse TOML mappings")?,
+        Format::Yaml => serde_yaml::from_str(text).context("Failed to parse YAML mappings")?,
+    })
+}
+
+fn parse_file(text: &str, format: Format) -> Result<ParsedFile> {
+    // the version is read on its own first, so errors in the rest of the file point at the right place.
+    // A version that isn't a number is the output of a chord named `version` in a plain file.
+    let version = deserialize::<FileVersion>(text, format)?
+        .version
+        .and_then(|version| version.as_u64());
+    let (include, remove, space, entries) = match version {
+        Some(version) if version == MAPPINGS_VERSION as u64 => {
+            let file: VersionedFile = deserialize(text, format)?;
+            (file.include, file.remove, file.space, file.mappings.0)
+        }
+        Some(version) => bail!(
+            "Unsupported mappings version {}, expected {}",
+            version,
+            MAPPINGS_VERSION
+        ),
+        None => {
+            let mappings: OrderedEntries = deserialize(text, format)?;
+            (Vec::new(), Vec::new(), Space::default(), mappings.0)
+        }
+    };
+
+    // serde doesn't keep track of positions, so look for the keys in the text.
+    // Entries are in the order of the file, so each one is after the previous one.
+    let mut offset = 0;
+    let entries = entries
+        .into_iter()
+        .map(|(chord, value)| {
+            // quoted or bare, followed by `:` in JSON and YAML, and `=` or the `]` of a table header in TOML.
+            // Keys start a line, or follow the `{` or `,` of an inline object or the `.` of a dotted TOML key,
+            // so the end of a longer key or of a word like `version` doesn't count.
+            let key_pattern = format!(
+                r#"(?m)(?:^|[{{,.])[ \t]*({}|'{}'|{})\s*[:=\]]"#,
+                regex::escape(&serde_json::to_string(&chord).unwrap()),
+                regex::escape(&chord),
+                regex::escape(&chord)
+            );
+            // search from the start of the line, so a key right at the offset still starts it
+            let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+            if let Some(key) = regex::Regex::new(&key_pattern)
+                .unwrap()
+                .captures_iter(&text[line_start..])
+                .filter_map(|captures| captures.get(1))
+                .find(|key| line_start + key.start() >= offset)
+            {
+                offset = line_start + key.start();
+            }
+            let location = Location::from_offset(text, offset);
+            offset += text[offset..].chars().next().map_or(0, char::len_utf8);
+            MappingEntry::new(chord, value, space, location)
+        })
+        .collect::<Result<_>>()?;
+    Ok(ParsedFile {
+        include,
+        remove,
+        entries,
+    })
+}
+
+/// Everything read from a mappings file and the files it includes.
+#[derive(Debug, Clone, Default)]
+pub struct MappingsSource {
+    /// the entries in order of precedence, later ones replacing earlier ones with the same chord
+    pub entries: Vec<MappingEntry>,
+    /// every file that was read, each after the files it includes
+    pub files: Vec<PathBuf>,
+}
+
+impl MappingsSource {
+    /// Read a mappings file together with the files it includes.
+    /// Included files come first, in the order they are listed, so the including file overrides them.
+    pub fn load(path: &Path, chord_layout: Option<&Layout>) -> Result<Self> {
+        let mut source = MappingsSource::default();
+        source.load_file(path, chord_layout, &mut Vec::new())?;
+        Ok(source)
+    }
+
+    fn load_file(
+        &mut self,
+        path: &Path,
+        chord_layout: Option<&Layout>,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Failed to open mappings file {}", path.display()))?;
+        if including.contains(&path) {
+            bail!("{} includes itself", path.display());
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to open mappings file {}", path.display()))?;
+        let parsed = parse_file(&text, Format::from_path(&path)?)
+            .with_context(|| format!("Invalid mappings file {}", path.display()))?;
+
+        let inherited_start = self.entries.len();
+        including.push(path.clone());
+        for include in &parsed.include {
+            let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+            self.load_file(&dir.join(include), chord_layout, including)?;
+        }
+        including.pop();
+
+        let removed = parsed
+            .remove
+            .iter()
+            .map(|chord| Chord::from_key_codes(parse_keys(chord, chord_layout)))
+            .collect::<Vec<_>>();
+        let inherited = self.entries.split_off(inherited_start);
+        self.entries.extend(
+            inherited
+                .into_iter()
+                .filter(|entry| !removed.contains(&entry.parse_chord(chord_layout).unordered())),
+        );
+
+        let file: Arc<Path> = Arc::from(path.as_path());
+        self.entries
+            .extend(parsed.entries.into_iter().map(|mut entry| {
+                entry.location.file = Some(file.clone());
+                entry
+            }));
+        self.files.push(path);
+        Ok(())
+    }
+}
+
+/// How the output of an entry is written: a list only if it has alternatives.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OutputsOut<'a> {
+    One(&'a str),
+    Candidates(Vec<&'a str>),
+}
+
+impl<'a> From<&'a MappingEntry> for OutputsOut<'a> {
+    fn from(entry: &'a MappingEntry) -> Self {
+        if entry.alternatives.is_empty() {
+            OutputsOut::One(&entry.output)
+        } else {
+            OutputsOut::Candidates(
+                Some(&entry.output)
+                    .into_iter()
+                    .chain(&entry.alternatives)
+                    .map(String::as_str)
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// How an entry is written: just the output if all its options are the defaults.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum EntryOut<'a> {
+    Output(OutputsOut<'a>),
+    Entry {
+        output: OutputsOut<'a>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        ordered: bool,
+        #[serde(skip_serializing_if = "is_default")]
+        space: Space,
+        #[serde(skip_serializing_if = "is_default")]
+        case: Case,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        comment: &'a Option<String>,
+        #[serde(skip_serializing_if = "<[_]>::is_empty")]
+        tags: &'a [String],
+    },
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl<'a> From<&'a MappingEntry> for EntryOut<'a> {
+    fn from(entry: &'a MappingEntry) -> Self {
+        let is_plain = !entry.ordered
+            && is_default(&entry.space)
+            && is_default(&entry.case)
+            && entry.comment.is_none()
+            && entry.tags.is_empty();
+        if is_plain {
+            EntryOut::Output(OutputsOut::from(entry))
+        } else {
+            EntryOut::Entry {
+                output: OutputsOut::from(entry),
+                ordered: entry.ordered,
+                space: entry.space,
+                case: entry.case,
+                comment: &entry.comment,
+                tags: &entry.tags,
+            }
+        }
+    }
+}
+
+/// Entries written as an object, in the order they are given in.
+struct EntriesOut<'a>(Vec<&'a MappingEntry>);
+
+impl Serialize for EntriesOut<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for entry in &self.0 {
+            map.serialize_entry(&entry.chord, &EntryOut::from(*entry))?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Serialize)]
+struct MappingsFileOut<'a> {
+    version: u32,
+    mappings: EntriesOut<'a>,
+}
+
+/// Write entries as a versioned mappings file.
+pub fn write_entries(entries: &[MappingEntry], format: Format) -> Result<String> {
+    let mut entries = entries.iter().collect::<Vec<_>>();
+    if format == Format::Toml {
+        // TOML needs all plain values to come before the tables
+        entries.sort_by_key(|entry| !matches!(EntryOut::from(*entry), EntryOut::Output(_)));
+    }
+    let file = MappingsFileOut {
+        version: MAPPINGS_VERSION,
+        mappings: EntriesOut(entries),
+    };
+    Ok(match format {
+        Format::Json => serde_json::to_string_pretty(&file)? + "\n",
+        Format::Toml => toml::to_string(&file)?,
+        Format::Yaml => serde_yaml::to_string(&file)?,
+    })
+}
+
+/// The outputs of a chord, together with the entry that defined it.
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    /// the candidate outputs, in the order repeating the chord types them in. There is always at least one.
+    pub outputs: Vec<Vec<OutputChar>>,
+    pub space: Space,
+    /// the chord, as written in the mappings file
+    pub chord: String,
+    pub location: Location,
+}
+
 #[derive(Debug)]
 pub struct Mappings {
-    mappings: HashMap<Chord, Vec<OutputChar>>,
+    mappings: HashMap<Chord, Mapping>,
+    /// the files the mappings were read from
+    files: Vec<PathBuf>,
 }
 
 impl Mappings {
-    pub fn from_reader<R: std::io::Read>(reader: R) -> Result<Self> {
-        let mappings: HashMap<String, String> =
-            serde_json::from_reader(reader).context("Failed to parse mappings")?;
-        let mappings: HashMap<Chord, Vec<OutputChar>> = mappings
-            .into_iter()
-            .map(|(k, v)| Ok((Chord::from_string(k), output_chars_from_string(&v)?)))
-            .collect::<Result<_>>()?;
+    /// Parse a JSON mappings file. The outputs are typed with the keys of `layout`,
+    /// and the chords are read as the keys typing them on `chord_layout`.
+    /// Without a layout, a US layout is assumed.
+    pub fn from_reader<R: std::io::Read>(
+        mut reader: R,
+        layout: Option<&Layout>,
+        chord_layout: Option<&Layout>,
+    ) -> Result<Self> {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .context("Failed to read mappings")?;
+        Mappings::from_entries(&parse_entries(&text, Format::Json)?, layout, chord_layout)
+    }
 
-        Ok(Mappings { mappings })
+    /// Build the mappings from the entries of a mappings file.
+    /// Entries with the same chord as an earlier one replace it.
+    pub fn from_entries(
+        entries: &[MappingEntry],
+        layout: Option<&Layout>,
+        chord_layout: Option<&Layout>,
+    ) -> Result<Self> {
+        let mut mappings = HashMap::new();
+        for entry in entries {
+            let outputs = entry.parse_outputs(layout).with_context(|| {
+                format!("Invalid output for {:?} at {}", entry.chord, entry.location)
+            })?;
+            let mapping = Mapping {
+                outputs,
+                space: entry.space,
+                chord: entry.chord.clone(),
+                location: entry.location.clone(),
+            };
+            mappings.insert(entry.parse_chord(chord_layout), mapping);
+        }
+        Ok(Mappings {
+            mappings,
+            files: Vec::new(),
+        })
     }
 
-    pub fn lookup(&self, chord: &Chord) -> Option<&Vec<OutputChar>> {
-        self.mappings.get(chord)
+    /// Build the mappings from a mappings file and its includes.
+    pub fn from_source(
+        source: &MappingsSource,
+        layout: Option<&Layout>,
+        chord_layout: Option<&Layout>,
+    ) -> Result<Self> {
+        Ok(Mappings {
+            files: source.files.clone(),
+            ..Mappings::from_entries(&source.entries, layout, chord_layout)?
+        })
+    }
+
+    /// The files the mappings were read from, if they were read from files.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Look up the candidate outputs of a chord, the one to type first first.
+    /// For an ordered chord, mappings that match the exact key order take precedence over unordered ones.
+    pub fn lookup(&self, chord: &Chord) -> Option<&[Vec<OutputChar>]> {
+        self.lookup_mapping(chord)
+            .map(|mapping| mapping.outputs.as_slice())
+    }
+
+    /// The mapping of the given chord, falling back to the unordered chord for ordered ones.
+    pub fn lookup_mapping(&self, chord: &Chord) -> Option<&Mapping> {
+        match self.mappings.get(chord) {
+            Some(mapping) => Some(mapping),
+            None if chord.is_ordered() => self.mappings.get(&chord.unordered()),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use itertools::Itertools;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_ordered_lookup() {
+        let mappings = Mappings::from_reader(
+            r#"{
+                "ab": "unordered",
+                "ba": { "output": "ordered", "ordered": true }
+            }"#
+            .as_bytes(),
+            None,
+            None,
+        )
+        .unwrap();
+        let lookup = |keys: &str| {
+            mappings
+                .lookup(&Chord::ordered_from_string(keys))
+                .map(|outputs| outputs[0].iter().map(|c| c.to_string()).collect::<String>())
+        };
+        assert_eq!(Some("ordered".to_owned()), lookup("ba"));
+        assert_eq!(Some("unordered".to_owned()), lookup("ab"));
+        assert_eq!(None, lookup("bc"));
+    }
+
+    #[test]
+    fn test_entry_locations() {
+        let entries = parse_entries(
+            "{\n  \"ab\": \"first\",\n  \"ab\": \"second\",\n    \"c\\\"d\": { \"output\": \"ab\", \"ordered\": true }\n}",
+            Format::Json,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![("ab", (2, 3)), ("ab", (3, 3)), ("c\"d", (4, 5)),],
+            entries
+                .iter()
+                .map(|entry| (
+                    entry.chord.as_str(),
+                    (entry.location.line, entry.location.column)
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert!(entries[2].ordered);
+
+        let mappings = Mappings::from_entries(&entries, None, None).unwrap();
+        assert_eq!(2, mappings.len());
+        assert_eq!(
+            Some("second".to_owned()),
+            mappings
+                .lookup(&Chord::from_string("ab"))
+                .map(|outputs| outputs[0].iter().join(""))
+        );
+    }
+
+    #[test]
+    fn test_formats() {
+        let toml = r#"
+            version = 2
+
+            [mappings]
+            ab = "about"
+
+            [mappings.th]
+            output = "the"
+            ordered = true
+            space = "after"
+            tags = ["common"]
+        "#;
+        let yaml = r#"
+            version: 2
+            mappings:
+              ab: about
+              # the most common word
+              th:
+                output: the
+                ordered: true
+                space: after
+                tags: [common]
+        "#;
+        let json = r#"{
+            "version": 2,
+            "mappings": {
+                "ab": "about",
+                "th": { "output": "the", "ordered": true, "space": "after", "tags": ["common"],
+                        "comment": "the most common word" }
+            }
+        }"#;
+        for (text, format, locations) in &[
+            (toml, Format::Toml, [(5, 13), (7, 23)]),
+            (yaml, Format::Yaml, [(4, 15), (6, 15)]),
+            (json, Format::Json, [(4, 17), (5, 17)]),
+        ] {
+            let entries = parse_entries(text, *format).unwrap();
+            assert_eq!(
+                vec![
+                    ("ab", false, Space::None, vec![]),
+                    ("th", true, Space::After, vec!["common".to_owned()]),
+                ],
+                entries
+                    .iter()
+                    .map(|e| (e.chord.as_str(), e.ordered, e.space, e.tags.clone()))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                locations.to_vec(),
+                entries
+                    .iter()
+                    .map(|e| (e.location.line, e.location.column))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                "the ",
+                entries[1].parse_output(None).unwrap().iter().join("")
+            );
+        }
+
+        assert!(parse_entries(r#"{"version": 3, "mappings": {}}"#, Format::Json).is_err());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mappings = Mappings::from_reader(
+            r#"{
+                "ab": ["their", "there", "they're"],
+                "cd": { "output": ["to", "too"], "case": "upper", "space": "after" }
+            }"#
+            .as_bytes(),
+            None,
+            None,
+        )
+        .unwrap();
+        let lookup = |keys: &str| {
+            mappings
+                .lookup(&Chord::from_string(keys))
+                .unwrap()
+                .iter()
+                .map(|output| output.iter().join(""))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["their", "there", "they're"], lookup("ab"));
+        assert_eq!(vec!["TO ", "TOO "], lookup("cd"));
+
+        assert!(parse_entries(r#"{"ab": []}"#, Format::Json).is_err());
+    }
+
+    #[test]
+    fn test_locations_of_tricky_keys() {
+        let locations = |text: &str, format: Format| {
+            parse_entries(text, format)
+                .unwrap()
+                .iter()
+                .map(|e| (e.chord.clone(), e.location.line, e.location.column))
+                .collect::<Vec<_>>()
+        };
+        // the keys are also the ends of `[mappings]`, `version` and `space`
+        let toml =
+            "version = 2\nspace = \"after\"\n\n[mappings]\ngs = \"x\"\non = \"y\"\nce = \"z\"\n";
+        assert_eq!(
+            vec![
+                ("gs".to_owned(), 5, 1),
+                ("on".to_owned(), 6, 1),
+                ("ce".to_owned(), 7, 1)
+            ],
+            locations(toml, Format::Toml)
+        );
+        let yaml = "version: 2\nmappings:\n  ö: x\n  ab: y\n  b: z\n";
+        assert_eq!(
+            vec![
+                ("ö".to_owned(), 3, 3),
+                ("ab".to_owned(), 4, 3),
+                ("b".to_owned(), 5, 3)
+            ],
+            locations(yaml, Format::Yaml)
+        );
+        let json = r#"{ "ab": "x", "b": "y" }"#;
+        assert_eq!(
+            vec![("ab".to_owned(), 1, 3), ("b".to_owned(), 1, 14)],
+            locations(json, Format::Json)
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text: &str, format: Format| match parse_entries(text, format) {
+            Err(err) => format!("{:#}", err),
+            Ok(_) => panic!("{} parsed fine", text),
+        };
+        assert_eq!(
+            "Failed to parse JSON mappings: unknown variant `afterr`, expected one of `none`, `after`, `auto` at line 4 column 64",
+            error(
+                r#"{
+                    "version": 2,
+                    "mappings": {
+                        "ab": { "output": "x", "space": "afterr" }
+                    }
+                }"#,
+                Format::Json
+            )
+        );
+        assert_eq!(
+            "Failed to parse YAML mappings: mappings.ab.ordered: invalid type: string \"yes\", expected a boolean at line 3 column 29",
+            error(
+                "version: 2\nmappings:\n  ab: { output: x, ordered: \"yes\" }\n",
+                Format::Yaml
+            )
+        );
+        assert!(error(
+            "version = 2\n[mappings]\nab = { output = \"x\", spaec = \"after\" }\n",
+            Format::Toml
+        )
+        .contains("unknown field `spaec`"));
+        assert!(
+            error(r#"{ "ab": { "output": "x" }, "cd": 3 }"#, Format::Json)
+                .contains("expected an output, a list of outputs, or an object")
+        );
+        assert_eq!(
+            "Unsupported mappings version 1, expected 2",
+            error(r#"{ "version": 1, "mappings": {} }"#, Format::Json)
+        );
+    }
+
+    #[test]
+    fn test_case() {
+        assert_eq!("HELLO<enter>", Case::Upper.apply("hello<enter>"));
+        assert_eq!(
+            "<ctrl-a>Hello world",
+            Case::Capitalize.apply("<ctrl-a>hello world")
+        );
+        assert_eq!("iphone<BackSpace>", Case::Lower.apply("iPhone<BackSpace>"));
+        assert_eq!("iPhone", Case::Keep.apply("iPhone"));
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("chordthingy-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("team")).unwrap();
+        std::fs::write(
+            dir.join("team").join("base.json"),
+            r#"{"ab": "about", "th": "the", "xy": "wrong"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("personal.yaml"),
+            "version: 2\ninclude: [team/base.json]\nremove: [yx]\nmappings:\n  ab: abort\n",
+        )
+        .unwrap();
+
+        let source = MappingsSource::load(&dir.join("personal.yaml"), None).unwrap();
+        let mappings = Mappings::from_source(&source, None, None).unwrap();
+        let lookup = |keys: &str| {
+            mappings
+                .lookup(&Chord::from_string(keys))
+                .map(|outputs| outputs[0].iter().join(""))
+        };
+        assert_eq!(Some("abort".to_owned()), lookup("ab"));
+        assert_eq!(Some("the".to_owned()), lookup("th"));
+        assert_eq!(None, lookup("xy"));
+
+        let base = dir.join("team").join("base.json").canonicalize().unwrap();
+        let personal = dir.join("personal.yaml").canonicalize().unwrap();
+        assert_eq!(vec![base.clone(), personal], mappings.files());
+        assert_eq!(
+            Some(base.as_path()),
+            mappings
+                .iter()
+                .find(|mapping| mapping.chord == "th")
+                .and_then(|mapping| mapping.location.file.as_deref())
+        );
+
+        std::fs::write(
+            dir.join("team").join("base.json"),
+            r#"{"version": 2, "include": ["../personal.yaml"]}"#,
+        )
+        .unwrap();
+        assert!(MappingsSource::load(&dir.join("personal.yaml"), None).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_entries() {
+        let toml = r#"
+            version = 2
+
+            [mappings]
+            ab = "about"
+            "c d" = "<enter>"
+            tr = ["their", "there"]
+
+            [mappings.th]
+            output = "the"
+            ordered = true
+            space = "after"
+            tags = ["common"]
+        "#;
+        let entries = parse_entries(toml, Format::Toml).unwrap();
+        for format in &[Format::Json, Format::Toml, Format::Yaml] {
+            let written = write_entries(&entries, *format).unwrap();
+            let reparsed = parse_entries(&written, *format).unwrap();
+            assert_eq!(
+                entries
+                    .iter()
+                    .map(|e| (
+                        &e.chord,
+                        &e.output,
+                        &e.alternatives,
+                        e.ordered,
+                        e.space,
+                        &e.tags
+                    ))
+                    .collect::<Vec<_>>(),
+                reparsed
+                    .iter()
+                    .map(|e| (
+                        &e.chord,
+                        &e.output,
+                        &e.alternatives,
+                        e.ordered,
+                        e.space,
+                        &e.tags
+                    ))
+                    .collect::<Vec<_>>()
+            );
+        }
     }
 }
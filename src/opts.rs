@@ -0,0 +1,222 @@
+use anyhow::*;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+use crate::keyboard::{discovery::DeviceSelector, key_code::KeyCode, layout::XkbNames};
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "chordthingy",
+    about = "Type whole words by pressing chords on a normal keyboard"
+)]
+pub struct Opt {
+    /// Mappings file to use, in JSON, TOML or YAML, depending on its extension.
+    /// Defaults to `mappings.json`, `mappings.toml` or `mappings.yaml` in `$XDG_CONFIG_HOME/chordthingy`
+    #[structopt(short, long, global = true, parse(from_os_str))]
+    pub mappings: Option<PathBuf>,
+
+    /// Device to read from, given as a path, a `vendor:product` id or a device name.
+    /// May be given multiple times. Defaults to the first keyboard that is found
+    #[structopt(short, long = "device", number_of_values = 1)]
+    pub devices: Vec<DeviceSelector>,
+
+    /// Keep a separate chord state for every device, so keys of different devices never form a chord
+    #[structopt(long)]
+    pub per_device: bool,
+
+    /// Maximum time in milliseconds between the first and the last key of a chord going down
+    #[structopt(long)]
+    pub press_window: Option<u64>,
+
+    /// Minimum time in milliseconds all keys of a chord need to be held down together
+    #[structopt(long)]
+    pub min_hold: Option<u64>,
+
+    /// Key that suspends chord detection while it is held, such as `<capslock>`.
+    /// May be given multiple times. Ctrl, Alt and Meta always do so
+    #[structopt(long = "suspend-key", number_of_values = 1)]
+    pub suspend_keys: Vec<KeyCode>,
+
+    /// Chord that undoes the last chord, erasing its output and typing its keys instead, such as `<backspace>u`.
+    /// A single key, like `<pause>`, works as well, but then can't be typed anymore
+    #[structopt(long)]
+    pub undo: Option<String>,
+
+    /// Chord that replaces the output of the last chord with its next candidate output, such as `<tab>n`.
+    /// Repeating a chord with several candidate outputs does so as well
+    #[structopt(long)]
+    pub next_candidate: Option<String>,
+
+    /// Don't capitalize the output of chords typed right after the end of a sentence
+    #[structopt(long)]
+    pub no_sentence_case: bool,
+
+    /// xkb layout the output of the mappings is typed in, such as `de`.
+    /// Defaults to `XKB_DEFAULT_LAYOUT`. Without either, a US layout is assumed
+    #[structopt(long, global = true)]
+    pub xkb_layout: Option<String>,
+
+    /// xkb variant of the layout, such as `colemak`
+    #[structopt(long, global = true)]
+    pub xkb_variant: Option<String>,
+
+    /// xkb keyboard model, such as `pc105`
+    #[structopt(long, global = true)]
+    pub xkb_model: Option<String>,
+
+    /// xkb rules the keymap is built with, such as `evdev`
+    #[structopt(long, global = true)]
+    pub xkb_rules: Option<String>,
+
+    /// xkb options, such as `lv3:ralt_switch`
+    #[structopt(long, global = true)]
+    pub xkb_options: Option<String>,
+
+    /// Read the chords of the mappings in the xkb layout as well, instead of by their keys on a US layout.
+    /// With a Colemak layout, `arst` then means the keys that type those letters
+    #[structopt(long, global = true)]
+    pub layout_chords: bool,
+
+    /// JSON file assigning keys to fingers, like `{"left index": "rtfgvb"}`,
+    /// used to warn about chords that need one finger to press several keys
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub fingers: Option<PathBuf>,
+
+    /// Also reload the mappings file on SIGHUP. It is always reloaded when it changes
+    #[structopt(long)]
+    pub reload_on_sighup: bool,
+
+    /// Print every chord that is typed. Given twice, also print the loaded mappings
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Only print what would be typed, without grabbing any device or writing any output
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Don't grab the devices, so their keys reach the applications as usual.
+    /// The characters typed by the keys of a chord are erased before its output is typed
+    #[structopt(long)]
+    pub no_grab: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum Command {
+    /// List all keyboards, together with their names and capabilities
+    ListDevices,
+    /// List all mappings, together with the file and line they are defined in
+    List,
+    /// Check a mappings file for errors, without touching any devices
+    Check {
+        /// The mappings file to check. Defaults to the one given by `--mappings`
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// Convert a Plover dictionary into mappings, skipping the entries that can't be a single chord
+    ImportPlover {
+        /// The Plover JSON dictionary to convert
+        #[structopt(parse(from_os_str))]
+        dictionary: PathBuf,
+        /// A JSON object of the keys pressed for each steno key, like `{"S-": "q"}`.
+        /// Steno keys that aren't given use Plover's QWERTY layout
+        #[structopt(long, parse(from_os_str))]
+        steno_map: Option<PathBuf>,
+        /// Where to write the mappings, in the format given by its extension. Defaults to JSON on stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Convert a CSV chord library of a hardware chorder, with rows like `a + d + e,made`, into mappings
+    ImportCsv {
+        /// The CSV file to convert
+        #[structopt(parse(from_os_str))]
+        library: PathBuf,
+        /// Where to write the mappings, in the format given by its extension. Defaults to JSON on stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Propose a chord for each word of a word list, without replacing any of the existing mappings
+    Generate {
+        /// Word list with a word and, optionally, its frequency on each line, like `the 23135851162`
+        #[structopt(parse(from_os_str))]
+        words: PathBuf,
+        /// Text whose consecutive letters are avoided as chords, as they are typed together when typing quickly
+        #[structopt(long, parse(from_os_str))]
+        corpus: Option<PathBuf>,
+        /// The most keys of a generated chord
+        #[structopt(long, default_value = "4")]
+        max_keys: usize,
+        /// How often the keys of a chord may follow each other in the corpus before the chord is avoided
+        #[structopt(long, default_value = "2")]
+        max_rolls: usize,
+        /// Where to write the mappings, in the format given by its extension. Defaults to JSON on stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Write the mappings as a CSV chord library for a hardware chorder, skipping the ones it can't type
+    ExportCsv {
+        /// Where to write the CSV file. Defaults to stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+impl Opt {
+    /// The mappings file given on the command line, or the default one in the users config directory.
+    pub fn mappings_path(&self) -> Result<PathBuf> {
+        match &self.mappings {
+            Some(path) => Ok(path.clone()),
+            None => default_mappings_path(),
+        }
+    }
+
+    /// The xkb keymap to type the output in, if one was given on the command line or in the environment.
+    pub fn xkb_names(&self) -> Option<XkbNames> {
+        let given = [
+            &self.xkb_layout,
+            &self.xkb_variant,
+            &self.xkb_model,
+            &self.xkb_rules,
+            &self.xkb_options,
+        ]
+        .iter()
+        .any(|name| name.is_some());
+        let in_env =
+            std::env::var_os("XKB_DEFAULT_LAYOUT").is_some_and(|layout| !layout.is_empty());
+        if !given && !in_env {
+            return None;
+        }
+        Some(XkbNames {
+            rules: self.xkb_rules.clone().unwrap_or_default(),
+            model: self.xkb_model.clone().unwrap_or_default(),
+            layout: self.xkb_layout.clone().unwrap_or_default(),
+            variant: self.xkb_variant.clone().unwrap_or_default(),
+            options: self.xkb_options.clone(),
+        })
+    }
+}
+
+/// The first of `mappings.json`, `mappings.toml`, `mappings.yaml` and `mappings.yml` that exists in
+/// `$XDG_CONFIG_HOME/chordthingy`, where `XDG_CONFIG_HOME` defaults to `~/.config`.
+/// Without any of them, the JSON one.
+pub fn default_mappings_path() -> Result<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            std::env::var_os("HOME").context("Neither XDG_CONFIG_HOME nor HOME is set")?,
+        )
+        .join(".config"),
+    };
+    let dir = config_dir.join("chordthingy");
+    let candidates = ["json", "toml", "yaml", "yml"]
+        .iter()
+        .map(|ext| dir.join("mappings").with_extension(ext))
+        .collect::<Vec<_>>();
+    Ok(candidates
+        .iter()
+        .find(|path| path.exists())
+        .unwrap_or(&candidates[0])
+        .clone())
+}
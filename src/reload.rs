@@ -0,0 +1,150 @@
+use anyhow::*;
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::mappings::Mappings;
+
+/// Reload the mappings in the background whenever the file at `path` or one of the files it includes changes,
+/// and on SIGHUP if `on_sighup` is set.
+/// The new mappings are parsed with `load` and only replace the current ones if they don't contain any errors.
+pub fn watch_mappings<F>(
+    path: PathBuf,
+    mappings: Arc<RwLock<Mappings>>,
+    on_sighup: bool,
+    load: F,
+) -> Result<()>
+where
+    F: Fn(&Path) -> Result<Mappings> + Send + Sync + 'static,
+{
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Failed to open mappings file {}", path.display()))?;
+    let current = mappings.clone();
+    let reload = Arc::new(move |path: &Path| match load(path) {
+        Ok(new_mappings) => {
+            println!(
+                "Reloaded {} mappings from {}",
+                new_mappings.len(),
+                path.display()
+            );
+            *mappings.write().unwrap() = new_mappings;
+        }
+        Err(err) => eprintln!("Keeping the old mappings: {:#}", err),
+    });
+
+    let mut inotify = Inotify::init().context("Failed to initialize inotify")?;
+
+    if on_sighup {
+        let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])
+            .context("Failed to register a SIGHUP handler")?;
+        let reload = reload.clone();
+        let path = path.clone();
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                reload(&path);
+            }
+        });
+    }
+
+    let mut watched_dirs = HashMap::new();
+    let mut files = watched_files(&current, &path);
+    watch_dirs(&mut inotify, &mut watched_dirs, &files);
+
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let changed = match inotify.read_events_blocking(&mut buffer) {
+                Ok(mut events) => events.any(|event| {
+                    let changed_file = event
+                        .name
+                        .and_then(|name| Some(watched_dirs.get(&event.wd)?.join(name)));
+                    changed_file.is_some_and(|changed_file| files.contains(&changed_file))
+                }),
+                Err(err) => {
+                    eprintln!("Stopped watching {}: {}", path.display(), err);
+                    return;
+                }
+            };
+            if changed {
+                reload(&path);
+                // the includes might have changed
+                files = watched_files(&current, &path);
+                watch_dirs(&mut inotify, &mut watched_dirs, &files);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The mappings file at `path`, together with all the files it included when it was last loaded.
+fn watched_files(mappings: &RwLock<Mappings>, path: &Path) -> Vec<PathBuf> {
+    let mut files = mappings.read().unwrap().files().to_vec();
+    files.push(path.to_owned());
+    files
+}
+
+/// Watch the directories of the given files, unless they are watched already.
+/// Editors tend to replace files instead of writing to them, so the files themselves can't be watched.
+fn watch_dirs(
+    inotify: &mut Inotify,
+    watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+    files: &[PathBuf],
+) {
+    for dir in files.iter().filter_map(|file| file.parent()) {
+        if watched_dirs.values().any(|watched| watched == dir) {
+            continue;
+        }
+        match inotify.add_watch(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO) {
+            Ok(watch) => {
+                watched_dirs.insert(watch, dir.to_owned());
+            }
+            Err(err) => eprintln!("Failed to watch {}: {}", dir.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, Instant};
+
+    fn wait_for_len(mappings: &RwLock<Mappings>, len: usize) -> usize {
+        let start = Instant::now();
+        while mappings.read().unwrap().len() != len && start.elapsed() < Duration::from_secs(2) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        mappings.read().unwrap().len()
+    }
+
+    #[test]
+    fn test_reload_on_change() {
+        let dir = std::env::temp_dir().join(format!("chordthingy-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mappings.json");
+        std::fs::write(&path, r#"{"ab": "c"}"#).unwrap();
+
+        let load = |path: &Path| Mappings::from_reader(std::fs::File::open(path)?, None, None);
+        let mappings = Arc::new(RwLock::new(load(&path).unwrap()));
+        watch_mappings(path.clone(), mappings.clone(), false, load).unwrap();
+
+        std::fs::write(&path, r#"{"ab": "c", "cd": "e"}"#).unwrap();
+        assert_eq!(2, wait_for_len(&mappings, 2));
+
+        // a broken file keeps the old mappings around
+        std::fs::write(&path, r#"{"ab": "#).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(2, mappings.read().unwrap().len());
+
+        let replacement = dir.join("mappings.json.new");
+        std::fs::write(&replacement, r#"{"ab": "c", "cd": "e", "fg": "h"}"#).unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        assert_eq!(3, wait_for_len(&mappings, 3));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}